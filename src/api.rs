@@ -1,10 +1,10 @@
-use std::{fs, io::Read};
-
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use chrono::Utc;
-use futures::{SinkExt, StreamExt, TryFutureExt, TryStreamExt};
+use futures::{SinkExt, TryFutureExt, TryStreamExt};
 use hyper::StatusCode;
+use itertools::Itertools;
+use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -18,7 +18,7 @@ pub enum ApiError {
     BadResponse { status: StatusCode, request: String },
 }
 
-use crate::config_global::GlobalConfig;
+use crate::{config_global::GlobalConfig, config_local::JobConfig, net::NetworkConfig};
 
 #[derive(Serialize, Deserialize)]
 pub struct LoginRequestBody {
@@ -81,7 +81,7 @@ pub enum PullEvent {
     LogLine(String),
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Default, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Default, Clone, Debug)]
 pub struct PushQueryParams {
     pub project_slug: String,
     pub wait: Option<bool>,
@@ -89,9 +89,45 @@ pub struct PushQueryParams {
     pub job: String,
     pub container: String,
     pub image: String,
+    /// The job's limits and hints from buildrecall.toml, for the farm to enforce
+    pub timeout_secs: Option<u64>,
+    pub retries: Option<u32>,
+    /// Comma separated, ex: "infra,137"
+    pub retry_on: Option<String>,
+    pub cpus: Option<u32>,
+    /// In bytes
+    pub memory: Option<u64>,
+}
+
+/// Sent as the JSON body of the push request rather than in its URL. Run scripts
+/// can be long and hold env values, and the URL ends up in errors and logs.
+#[derive(serde::Serialize, serde::Deserialize, Default, Clone, Debug)]
+pub struct PushBody {
+    /// The job the way brr resolved it: with its includes merged, its template
+    /// applied and its `${vars}` filled in. The farm runs this rather than reading
+    /// buildrecall.toml from the pushed tree.
+    pub job_config: Option<JobConfig>,
+    /// One "<commit> <path>" line for each submodule or nested repo that was sent
+    /// as files, since the tree itself doesn't record their commits
+    pub submodules: Option<String>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Default, Clone)]
+impl PushQueryParams {
+    /// Adds the timeout, retry policy and resource hints of `job`
+    pub fn with_job_limits(self, job: &JobConfig) -> Self {
+        let retries = job.retries.filter(|r| *r > 0);
+        PushQueryParams {
+            timeout_secs: job.timeout.map(|t| t.0.as_secs()),
+            retries,
+            retry_on: retries.map(|_| job.retry_on().iter().join(",")),
+            cpus: job.cpus,
+            memory: job.memory.map(|m| m.0),
+            ..self
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default, Clone, Debug)]
 pub struct PullQueryParams {
     pub project_slug: String,
     pub tree_hash: String,
@@ -119,11 +155,20 @@ pub trait BuildRecall {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ApiClient {
     global_config: GlobalConfig,
+    // Built on first use, so that the CA bundle and client certificate are read once
+    #[serde(skip)]
+    network: OnceCell<NetworkConfig>,
+    #[serde(skip)]
+    http: OnceCell<reqwest::Client>,
 }
 
 impl ApiClient {
     pub fn new(global_config: GlobalConfig) -> ApiClient {
-        ApiClient { global_config }
+        ApiClient {
+            global_config,
+            network: OnceCell::new(),
+            http: OnceCell::new(),
+        }
     }
 
     pub fn get_control_host(&self) -> String {
@@ -138,16 +183,30 @@ impl ApiClient {
         self.global_config.clone().scheduler_ws_host()
     }
 
+    fn network(&self) -> Result<&NetworkConfig> {
+        self.network
+            .get_or_try_init(|| NetworkConfig::from_config(&self.global_config))
+    }
+
+    fn http(&self) -> Result<reqwest::Client> {
+        // Cheap to clone, it shares its connection pool
+        let client = self
+            .http
+            .get_or_try_init(|| self.network()?.http_client())?;
+        Ok(client.clone())
+    }
+
     fn token(&self) -> Result<String> {
         self.global_config.clone().access_token()
         .ok_or(
-            anyhow!("Can't find an 'access_token'. Specify one in your global config file (which typically lives at ~/.buildrecall/config) or if in CI, in the BUILDRECALL_API_KEY env var."))
+            anyhow!("Can't find an 'access_token'. Specify one in your global config file (which typically lives at ~/.config/buildrecall/config.toml) or if in CI, in the BUILDRECALL_API_KEY env var."))
     }
 
     //  returns whether logs have been printed
     async fn pull_artifact_url(&self, args: &PullQueryParams) -> Result<(PullOutput, bool)> {
         use tokio_tungstenite::tungstenite::http;
         use tokio_tungstenite::tungstenite::Message;
+        use tokio_tungstenite::Connector;
 
         let tok = self.token()?.clone();
         let query = serde_qs::to_string(&args)?;
@@ -156,7 +215,17 @@ impl ApiClient {
             .header(http::header::AUTHORIZATION, &format!("Bearer {}", tok))
             .body(())?;
 
-        let (mut ws, pullresp) = tokio_tungstenite::connect_async(req).await?;
+        // Connected by hand so that it goes through the proxy and TLS settings
+        let network = self.network()?;
+        let url = url::Url::parse(&req.uri().to_string())?;
+        let stream = network.connect(&url).await?;
+        let connector = match url.scheme() {
+            "wss" => Connector::NativeTls(network.tls_connector()?),
+            _ => Connector::Plain,
+        };
+        let (mut ws, pullresp) =
+            tokio_tungstenite::client_async_tls_with_config(req, stream, None, Some(connector))
+                .await?;
 
         if pullresp.status() == 401 {
             return Err(ApiError::Unauthorized.into());
@@ -202,7 +271,7 @@ impl ApiClient {
 #[async_trait]
 impl BuildRecall for ApiClient {
     async fn login(&self, body: LoginRequestBody) -> Result<LoginRequestResponseBody> {
-        let client = reqwest::Client::new();
+        let client = self.http()?;
 
         let resp = client
             .post(format!("{}/v1/cli/login", self.get_control_host()))
@@ -240,7 +309,7 @@ impl BuildRecall for ApiClient {
         secret_slug: String,
         value: String,
     ) -> Result<ProjectSecret> {
-        let client = reqwest::Client::new();
+        let client = self.http()?;
         #[derive(Deserialize, Serialize, Clone, Debug)]
         pub struct SetSecret {
             slug: String,
@@ -297,7 +366,10 @@ impl BuildRecall for ApiClient {
             .context("Failed to pull s3 signed url for this artifact")?;
 
         if !already_printed_logs {
-            let _ = reqwest::get(&pull.logs_url)
+            let _ = self
+                .http()?
+                .get(&pull.logs_url)
+                .send()
                 .and_then(|r| async {
                     if !r.status().is_success() {
                         return Ok(());
@@ -317,9 +389,10 @@ impl BuildRecall for ApiClient {
             None => return Ok(false),
         };
 
+        let network = self.network()?.clone();
         handle
             .spawn_blocking(move || -> Result<bool> {
-                let client = reqwest::blocking::Client::new();
+                let client = network.blocking_http_client()?;
 
                 let resp = client
                     .get(artifact_url)
@@ -335,7 +408,7 @@ impl BuildRecall for ApiClient {
     }
 
     async fn list_projects(&self) -> Result<Vec<Project>> {
-        let client = reqwest::Client::new();
+        let client = self.http()?;
         let tok = self.token()?;
 
         let resp = client
@@ -367,12 +440,12 @@ impl BuildRecall for ApiClient {
     }
 
     async fn create_project(&self, slug: String) -> Result<Project> {
-        let client = reqwest::Client::new();
+        let client = self.http()?;
         let tok = self.token()?;
 
         let resp = client
             .post(format!("{}/v1/cli/projects", self.get_control_host()))
-            .json(&CreateProjectBody { slug: slug })
+            .json(&CreateProjectBody { slug })
             .bearer_auth(tok)
             .send()
             .await
@@ -400,7 +473,7 @@ impl BuildRecall for ApiClient {
     }
 
     async fn invite(&self) -> Result<OrgInvite> {
-        let client = reqwest::Client::new();
+        let client = self.http()?;
         let tok = self.token()?;
 
         let resp = client
@@ -432,3 +505,28 @@ impl BuildRecall for ApiClient {
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PushQueryParams;
+    use crate::config_local::JobConfig;
+
+    #[test]
+    fn test_forwards_the_job_limits() {
+        let job: JobConfig = toml::from_str(
+            "run = \"make\"\ntimeout = \"45m\"\nretries = 2\nretry_on = [\"infra\", 137]\ncpus = 8\nmemory = \"16GB\"\n",
+        )
+        .unwrap();
+        let params = PushQueryParams {
+            project_slug: "app".to_string(),
+            job: "build".to_string(),
+            ..Default::default()
+        }
+        .with_job_limits(&job);
+
+        assert_eq!(
+            serde_qs::to_string(&params).unwrap(),
+            "project_slug=app&tree_hash=&job=build&container=&image=&timeout_secs=2700&retries=2&retry_on=infra%2C137&cpus=8&memory=17179869184"
+        );
+    }
+}
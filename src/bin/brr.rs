@@ -1,15 +1,31 @@
-use std::env;
+use std::{env, path::PathBuf};
 
 use anyhow::{Context, Result};
 use clap::{AppSettings, Clap};
 use init::AttachArguments;
 
-use brr::{run::JobArgs, *};
+use brr::{config_global::Setting, run::JobArgs, units::TimeSpan, *};
 
 #[derive(Clap, Debug)]
 #[clap(setting = AppSettings::ColoredHelp)]
 #[clap(author, about, version)]
 struct Opts {
+    /// Where to keep the config and shadow repos, instead of ~/.config/buildrecall
+    #[clap(long, global = true, env = "BUILDRECALL_CONFIG_DIR")]
+    config_dir: Option<PathBuf>,
+
+    /// The profile to use, instead of the current one
+    #[clap(long, global = true)]
+    profile: Option<String>,
+
+    /// The app to connect to, instead of the configured one
+    #[clap(long, global = true)]
+    control_domain: Option<String>,
+
+    /// The build farm scheduler to connect to, instead of the configured one
+    #[clap(long, global = true)]
+    scheduler_domain: Option<String>,
+
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
@@ -37,6 +53,21 @@ enum SubCommand {
 
     #[clap()]
     Secrets(Secrets),
+
+    #[clap()]
+    Gc(gc::Gc),
+
+    #[clap()]
+    Profile(Profile),
+
+    #[clap()]
+    Config(Config),
+
+    #[clap()]
+    Validate(validate::Validate),
+
+    #[clap()]
+    Schema(schema::Schema),
 }
 
 /// Creates a secret
@@ -46,6 +77,20 @@ struct Secrets {
     subcmd: secrets::SecretsSubCommand,
 }
 
+/// Switches between Build Recall servers, ex: production and staging
+#[derive(Clap, Debug)]
+struct Profile {
+    #[clap(subcommand)]
+    subcmd: profile::ProfileSubCommand,
+}
+
+/// Shows and changes brr's global config, or this project's buildrecall.toml
+#[derive(Clap, Debug)]
+struct Config {
+    #[clap(subcommand)]
+    subcmd: config::ConfigSubCommand,
+}
+
 /// Creates an invite link you can give to your team
 #[derive(Clap, Debug)]
 struct Invite {}
@@ -66,6 +111,23 @@ struct Run {
     /// The name of the job
     job: String,
     container: String,
+
+    /// Push even if new files are over the size limits in buildrecall.toml
+    #[clap(long)]
+    force: bool,
+
+    /// Show what would be uploaded and requested, without touching the network
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Print a JSON summary of the run (tree hash, what was pushed) to stdout
+    #[clap(long)]
+    json: bool,
+
+    /// Give up waiting for the job after this long, ex: 30m, not counting the push.
+    /// Defaults to what the job's timeout and retries allow, plus 10 minutes.
+    #[clap(long)]
+    timeout: Option<TimeSpan>,
 }
 
 #[derive(Clap, Debug)]
@@ -74,42 +136,85 @@ struct Push {}
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
+    let overrides = vec![
+        (Setting::Profile, opts.profile),
+        (Setting::ControlDomain, opts.control_domain),
+        (Setting::SchedulerDomain, opts.scheduler_domain),
+    ];
+    config_global::set_cli_overrides(
+        overrides
+            .into_iter()
+            .filter_map(|(setting, flag)| flag.map(|f| (setting, f)))
+            .collect(),
+    );
+    let config_dir_flag = opts.config_dir;
+    let global_config_dir = || match &config_dir_flag {
+        Some(dir) => Ok(dir.clone()),
+        None => get_global_config_dir(),
+    };
 
     // You can handle information about subcommands by requesting their matches by name
     // (as below), requesting just the name used, or both at the same time
     match opts.subcmd {
-        SubCommand::Login(l) => login::run_login(get_global_config_dir()?, l).await,
+        SubCommand::Login(l) => login::run_login(global_config_dir()?, l).await,
         SubCommand::Init(args) => {
-            init::run_attach(
-                get_global_config_dir()?,
-                AttachArguments { slug: args.name },
-            )
-            .await
+            init::run_attach(global_config_dir()?, AttachArguments { slug: args.name }).await
         }
         SubCommand::Secrets(s) => {
-            secrets::run_secrets(s.subcmd, get_global_config_dir()?, env::current_dir()?).await
+            secrets::run_secrets(s.subcmd, global_config_dir()?, env::current_dir()?).await
         }
         SubCommand::Run(a) => {
-            run::pull_with_push_if_needed(
-                get_global_config_dir()?,
-                env::current_dir()?,
-                JobArgs {
-                    job: a.job,
-                    container: a.container,
-                },
-            )
-            .await
+            let job_args = JobArgs {
+                job: a.job,
+                container: a.container,
+                force: a.force,
+                timeout: a.timeout,
+            };
+
+            if a.dry_run {
+                let output =
+                    run::dry_run(global_config_dir()?, env::current_dir()?, job_args).await?;
+                if a.json {
+                    println!("{}", serde_json::to_string_pretty(&output)?);
+                } else {
+                    print!("{}", output.describe());
+                }
+                return Ok(());
+            }
+
+            let output =
+                run::pull_with_push_if_needed(global_config_dir()?, env::current_dir()?, job_args)
+                    .await?;
+
+            if a.json {
+                // Not a debug log, this is the output of this command
+                println!("{}", serde_json::to_string_pretty(&output)?);
+            }
+            Ok(())
         }
         SubCommand::Hash(_) => {
             let curr = env::current_dir()?.as_path().to_path_buf();
-            let files = list_non_ignored_files_in_dir(&curr.clone())
+            // Without a buildrecall.toml, hash it the way a new project would sync
+            let sync = if curr.join(config_local::LOCAL_CONFIG_NAME).is_file() {
+                config_local::read_local_config(curr.clone())?.sync()
+            } else {
+                config_local::SyncConfig::default()
+            };
+            let files = list_non_ignored_files_in_dir(&curr.clone(), &sync)
                 .context("failed to list files in current dir")?;
-            let hash = hash::hash_files(&curr.clone(), files)
+            let hash = hash::hash_files(&curr.clone(), files, &sync)
                 .await
                 .context("failed to hash files")?;
             println!("{}", hash);
             Ok(())
         }
-        SubCommand::Invite(_) => invite::run_invite(get_global_config_dir()?).await,
+        SubCommand::Invite(_) => invite::run_invite(global_config_dir()?).await,
+        SubCommand::Gc(args) => gc::run_gc(global_config_dir()?, args).await,
+        SubCommand::Schema(s) => schema::run_schema(s).await,
+        SubCommand::Validate(v) => validate::run_validate(env::current_dir()?, v).await,
+        SubCommand::Config(c) => {
+            config::run_config(global_config_dir()?, env::current_dir()?, c.subcmd).await
+        }
+        SubCommand::Profile(p) => profile::run_profile(global_config_dir()?, p.subcmd).await,
     }
 }
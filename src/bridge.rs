@@ -0,0 +1,239 @@
+use anyhow::{anyhow, Context, Result};
+use std::{
+    future::Future,
+    io::{self, Read, Write},
+    sync::mpsc as std_mpsc,
+    thread,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+// The most a single read hands back, libgit2 asks for 64KiB at a time anyway
+const READ_CHUNK: usize = 64 * 1024;
+
+enum Request {
+    Read(usize),
+    Write(Vec<u8>),
+    Flush,
+}
+
+enum Reply {
+    Read(Vec<u8>),
+    Written(usize),
+    Flushed,
+}
+
+/// A blocking `Read` + `Write` over an async stream. The stream lives on its own
+/// thread with its own single-threaded runtime, so this works from any thread,
+/// whether or not (and however) the caller is running tokio. Every operation on
+/// the stream, and connecting it, gives up after `timeout`.
+pub struct BlockingStream {
+    requests: mpsc::UnboundedSender<Request>,
+    replies: std_mpsc::Receiver<io::Result<Reply>>,
+}
+
+impl BlockingStream {
+    /// Runs `connect` on a new thread named `name` and waits until it's done.
+    pub fn open<S, F, Fut>(name: &str, connect: F, timeout: Duration) -> Result<BlockingStream>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<S>>,
+    {
+        let (ready_tx, ready_rx) = std_mpsc::channel::<Result<()>>();
+        // One request is in flight at a time, callers wait for its reply before
+        // sending the next, so these never hold more than one message
+        let (requests, mut requests_rx) = mpsc::unbounded_channel();
+        let (replies_tx, replies) = std_mpsc::channel();
+
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                let runtime = match tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(r) => r,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e.into()));
+                        return;
+                    }
+                };
+
+                runtime.block_on(async move {
+                    let mut stream = match tokio::time::timeout(timeout, connect()).await {
+                        Ok(Ok(s)) => s,
+                        Ok(Err(e)) => {
+                            let _ = ready_tx.send(Err(e));
+                            return;
+                        }
+                        Err(_) => {
+                            let _ = ready_tx.send(Err(anyhow!(
+                                "Timed out after {}s while connecting",
+                                timeout.as_secs()
+                            )));
+                            return;
+                        }
+                    };
+                    let _ = ready_tx.send(Ok(()));
+
+                    let mut buf = vec![0; READ_CHUNK];
+                    while let Some(request) = requests_rx.recv().await {
+                        let op = async {
+                            match request {
+                                Request::Read(len) => {
+                                    let n = stream.read(&mut buf[..len.min(READ_CHUNK)]).await?;
+                                    Ok(Reply::Read(buf[..n].to_vec()))
+                                }
+                                Request::Write(data) => {
+                                    stream.write_all(&data).await?;
+                                    Ok(Reply::Written(data.len()))
+                                }
+                                Request::Flush => {
+                                    stream.flush().await?;
+                                    Ok(Reply::Flushed)
+                                }
+                            }
+                        };
+
+                        let reply = match tokio::time::timeout(timeout, op).await {
+                            Ok(r) => r,
+                            Err(_) => Err(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                format!(
+                                    "Timed out after {}s waiting on the connection",
+                                    timeout.as_secs()
+                                ),
+                            )),
+                        };
+
+                        // Whatever failed, the stream can't be trusted to be in sync anymore
+                        let failed = reply.is_err();
+                        if replies_tx.send(reply).is_err() || failed {
+                            break;
+                        }
+                    }
+                });
+            })
+            .context("Failed to start the connection thread")?;
+
+        ready_rx
+            .recv()
+            .map_err(|_| anyhow!("The connection thread exited while connecting"))??;
+
+        Ok(BlockingStream { requests, replies })
+    }
+
+    fn call(&self, request: Request) -> io::Result<Reply> {
+        let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "The connection is closed");
+
+        self.requests.send(request).map_err(|_| closed())?;
+        self.replies.recv().map_err(|_| closed())?
+    }
+}
+
+fn unexpected_reply() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Got the wrong reply from the connection",
+    )
+}
+
+impl Read for BlockingStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        match self.call(Request::Read(buf.len()))? {
+            Reply::Read(data) => {
+                buf[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            }
+            _ => Err(unexpected_reply()),
+        }
+    }
+}
+
+impl Write for BlockingStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.call(Request::Write(buf.to_vec()))? {
+            Reply::Written(n) => Ok(n),
+            _ => Err(unexpected_reply()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.call(Request::Flush)? {
+            Reply::Flushed => Ok(()),
+            _ => Err(unexpected_reply()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{ErrorKind, Read, Write},
+        sync::mpsc,
+        time::Duration,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::BlockingStream;
+
+    // Hands the other end of an in-memory stream back to the test
+    fn open_pair(timeout: Duration) -> (BlockingStream, mpsc::Receiver<tokio::io::DuplexStream>) {
+        let (server_tx, server_rx) = mpsc::channel();
+        let stream = BlockingStream::open(
+            "test-bridge",
+            move || async move {
+                let (client, server) = tokio::io::duplex(1024);
+                server_tx.send(server).unwrap();
+                Ok(client)
+            },
+            timeout,
+        )
+        .unwrap();
+        (stream, server_rx)
+    }
+
+    #[test]
+    fn test_round_trips_without_a_runtime() {
+        let (mut stream, server) = open_pair(Duration::from_secs(5));
+        let mut server = server.recv().unwrap();
+
+        let echo = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let mut buf = [0; 5];
+                server.read_exact(&mut buf).await.unwrap();
+                server.write_all(&buf).await.unwrap();
+            });
+        });
+
+        stream.write_all(b"hello").unwrap();
+        stream.flush().unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        echo.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_times_out_when_the_other_side_is_silent() {
+        // From inside a current-thread runtime, which used to panic
+        let (mut stream, _server) = open_pair(Duration::from_millis(100));
+
+        let err = stream.read(&mut [0; 16]).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        // And the connection is closed after that
+        assert!(stream.read(&mut [0; 16]).is_err());
+    }
+}
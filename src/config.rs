@@ -0,0 +1,245 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Clap;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config_edit::{self, check_known_key, edit_toml_file, parse_value, split_key, write_changes},
+    config_global::{read_global_config, GlobalConfig, Setting, Source},
+    config_local::{LocalConfig, CONFIG_FORMAT_VERSION, LOCAL_CONFIG_NAME},
+    migrate::{migrate_local_config, version_of},
+};
+
+/// Prints the global config file
+#[derive(Clap, Debug)]
+pub struct Show {
+    /// Print the connection settings brr will use, and where each one came from:
+    /// a flag, an environment variable, the config file or a default
+    #[clap(long)]
+    pub resolved: bool,
+}
+
+/// Prints a setting, ex: `brr config get connection.scheduler_domain`
+#[derive(Clap, Debug)]
+pub struct Get {
+    pub key: String,
+
+    /// From this project's buildrecall.toml, instead of the global config
+    #[clap(long)]
+    pub local: bool,
+}
+
+/// Changes a setting, keeping the rest of the file as it is.
+/// ex: `brr config set --local jobs.build.run "cargo build --release"`
+#[derive(Clap, Debug)]
+pub struct Set {
+    pub key: String,
+
+    /// Parsed as TOML if it can be, ex: 30, true or ["target"], and a string otherwise
+    pub value: String,
+
+    /// In this project's buildrecall.toml, instead of the global config
+    #[clap(long)]
+    pub local: bool,
+}
+
+/// Removes a setting, keeping the rest of the file as it is
+#[derive(Clap, Debug)]
+pub struct Unset {
+    pub key: String,
+
+    /// From this project's buildrecall.toml, instead of the global config
+    #[clap(long)]
+    pub local: bool,
+}
+
+/// Rewrites this project's buildrecall.toml in the current format, keeping its
+/// comments where it can. brr reads older formats too, this only saves upgrading
+/// them on every run.
+#[derive(Clap, Debug)]
+pub struct Migrate {
+    /// Print what would change, without writing anything
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clap, Debug)]
+pub enum ConfigSubCommand {
+    #[clap()]
+    Show(Show),
+
+    #[clap()]
+    Get(Get),
+
+    #[clap()]
+    Set(Set),
+
+    #[clap()]
+    Unset(Unset),
+
+    #[clap()]
+    Migrate(Migrate),
+}
+
+pub async fn run_config(
+    global_config_dir: PathBuf,
+    local_config_dir: PathBuf,
+    cmd: ConfigSubCommand,
+) -> Result<()> {
+    match cmd {
+        ConfigSubCommand::Get(g) => {
+            let path = config_path(&global_config_dir, &local_config_dir, g.local)?;
+            let keys = split_key(&g.key)?;
+            let contents =
+                fs::read_to_string(&path).context(format!("Can't read path {:?}", path))?;
+            let root: toml::Value =
+                toml::from_str(&contents).context(format!("Failed to parse {:?}", path))?;
+
+            match config_edit::get(&root, &keys) {
+                Some(toml::Value::String(s)) => println!("{}", s),
+                Some(t @ toml::Value::Table(_)) => print!("{}", toml::to_string_pretty(t)?),
+                Some(v) => println!("{}", v),
+                None => return Err(anyhow!("'{}' isn't set in {:?}", g.key, path)),
+            }
+            Ok(())
+        }
+        ConfigSubCommand::Set(s) => {
+            let path = config_path(&global_config_dir, &local_config_dir, s.local)?;
+            let keys = split_key(&s.key)?;
+            edit_toml_file(
+                &path,
+                |doc| config_edit::set(doc, &keys, parse_value(&s.value)),
+                |next| {
+                    if s.local {
+                        let config: LocalConfig = toml::from_str(next)
+                            .context(format!("Can't set {} to {}", s.key, s.value))?;
+                        check_known_key(&config, &keys)
+                    } else {
+                        let config: GlobalConfig = toml::from_str(next)
+                            .context(format!("Can't set {} to {}", s.key, s.value))?;
+                        config.validate()?;
+                        check_known_key(&config, &keys)
+                    }
+                },
+            )
+        }
+        ConfigSubCommand::Unset(u) => {
+            let path = config_path(&global_config_dir, &local_config_dir, u.local)?;
+            let keys = split_key(&u.key)?;
+            let mut removed = false;
+            edit_toml_file(
+                &path,
+                |doc| {
+                    removed = config_edit::unset(doc, &keys)?;
+                    Ok(())
+                },
+                |next| {
+                    if u.local {
+                        toml::from_str::<LocalConfig>(next)
+                            .context(format!("Can't unset {}", u.key))?;
+                    } else {
+                        toml::from_str::<GlobalConfig>(next)
+                            .context(format!("Can't unset {}", u.key))?
+                            .validate()?;
+                    }
+                    Ok(())
+                },
+            )?;
+            if !removed {
+                eprintln!("{} wasn't set in {:?}", u.key, path);
+            }
+            Ok(())
+        }
+        ConfigSubCommand::Migrate(m) => {
+            let path = config_path(&global_config_dir, &local_config_dir, true)?;
+            let contents =
+                fs::read_to_string(&path).context(format!("Can't read path {:?}", path))?;
+            let written: toml::value::Table =
+                toml::from_str(&contents).context(format!("Failed to parse {:?}", path))?;
+            let from = version_of(&written)?;
+
+            let mut current = written.clone();
+            let applied = migrate_local_config(&mut current)?;
+            if from == CONFIG_FORMAT_VERSION {
+                eprintln!(
+                    "{:?} is already in format version {}",
+                    path, CONFIG_FORMAT_VERSION
+                );
+                return Ok(());
+            }
+            current.insert(
+                "version".to_string(),
+                toml::Value::Integer(CONFIG_FORMAT_VERSION as i64),
+            );
+            toml::Value::Table(current.clone())
+                .try_into::<LocalConfig>()
+                .context(format!("Failed to parse {:?} once upgraded", path))?;
+
+            eprintln!(
+                "Upgrading {:?} from format version {} to {}:",
+                path, from, CONFIG_FORMAT_VERSION
+            );
+            for change in applied {
+                eprintln!("  {}", change);
+            }
+            eprintln!("  `version = {}` was added", CONFIG_FORMAT_VERSION);
+            if m.dry_run {
+                return Ok(());
+            }
+            write_changes(&path, &written, &current)
+        }
+        ConfigSubCommand::Show(s) => {
+            let config = read_global_config(global_config_dir.clone())?;
+            let filepath = global_config_dir.join("config.toml");
+
+            if !s.resolved {
+                let contents = fs::read_to_string(&filepath)
+                    .context(format!("Can't read path {:?}", filepath))?;
+                eprintln!("# {:?}", filepath);
+                print!("{}", contents);
+                return Ok(());
+            }
+
+            eprintln!("# Resolved from {:?} and the environment", filepath);
+            for setting in Setting::ALL.iter() {
+                let (value, source) = config.resolve(*setting);
+                println!("{}", describe(*setting, value, &source));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn config_path(global_config_dir: &Path, local_config_dir: &Path, local: bool) -> Result<PathBuf> {
+    if !local {
+        fs::create_dir_all(global_config_dir)
+            .context(format!("Failed to create dir {:?}", global_config_dir))?;
+        return Ok(global_config_dir.join("config.toml"));
+    }
+
+    let path = local_config_dir.join(LOCAL_CONFIG_NAME);
+    if !path.is_file() {
+        return Err(anyhow!(
+            "There's no {} in {:?}, run `brr init` to create one",
+            LOCAL_CONFIG_NAME,
+            local_config_dir
+        ));
+    }
+    Ok(path)
+}
+
+fn describe(setting: Setting, value: Option<String>, source: &Source) -> String {
+    let key = match setting {
+        Setting::Profile => "profile",
+        _ => setting.key(),
+    };
+    let value = match value {
+        Some(_) if setting.is_secret() => "\"********\"".to_string(),
+        Some(v) => format!("{:?}", v),
+        None => return format!("# {} is unset", key),
+    };
+
+    format!("{} = {} # {}", key, value, source)
+}
@@ -0,0 +1,293 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::{fs, path::Path};
+use toml_edit::{decorated, table, Document, InlineTable, Item, Table, Value};
+
+/// Parses a value given on the command line. Anything that's valid TOML keeps its
+/// type, ex: `30`, `true` or `["target"]`, everything else is a string.
+pub fn parse_value(raw: &str) -> Value {
+    raw.parse::<Value>().unwrap_or_else(|_| Value::from(raw))
+}
+
+/// Splits "connection.scheduler_domain" into its keys
+pub fn split_key(key: &str) -> Result<Vec<&str>> {
+    let keys: Vec<&str> = key.split('.').collect();
+    if keys.iter().any(|k| k.is_empty()) {
+        return Err(anyhow!(
+            "'{}' isn't a valid key, expected something like 'connection.scheduler_domain'",
+            key
+        ));
+    }
+    Ok(keys)
+}
+
+/// Looks up a dotted key in a parsed TOML file
+pub fn get<'a>(root: &'a toml::Value, keys: &[&str]) -> Option<&'a toml::Value> {
+    keys.iter().try_fold(root, |value, key| value.get(key))
+}
+
+/// Sets a dotted key, creating the tables on the way. Everything else in the
+/// document, comments included, stays as it was.
+pub fn set(doc: &mut Document, keys: &[&str], value: Value) -> Result<()> {
+    set_in_table(doc.as_table_mut(), keys, value)
+}
+
+/// Removes a dotted key, returns whether it was there
+pub fn unset(doc: &mut Document, keys: &[&str]) -> Result<bool> {
+    unset_in_table(doc.as_table_mut(), keys)
+}
+
+fn set_in_table(t: &mut Table, keys: &[&str], value: Value) -> Result<()> {
+    let (key, rest) = keys.split_first().ok_or(anyhow!("The key is empty"))?;
+    let item = t.entry(key);
+
+    if rest.is_empty() {
+        if item.is_table() || item.is_array_of_tables() {
+            return Err(anyhow!(
+                "'{}' is a table, set one of the keys in it instead",
+                key
+            ));
+        }
+        *item = Item::Value(replacing(item.as_value(), value));
+        return Ok(());
+    }
+
+    if item.is_none() {
+        *item = table();
+        // Only the innermost table gets a [header]
+        if let Some(t) = item.as_table_mut() {
+            t.set_implicit(true);
+        }
+    }
+    match item {
+        Item::Table(t) => set_in_table(t, rest, value),
+        Item::Value(Value::InlineTable(t)) => set_in_inline_table(t, rest, value),
+        _ => Err(anyhow!("'{}' isn't a table", key)),
+    }
+}
+
+fn set_in_inline_table(t: &mut InlineTable, keys: &[&str], value: Value) -> Result<()> {
+    let (key, rest) = keys.split_first().ok_or(anyhow!("The key is empty"))?;
+
+    if rest.is_empty() {
+        let next = replacing(t.get(key), value);
+        t.remove(key);
+        t.get_or_insert(key, next);
+        t.fmt();
+        return Ok(());
+    }
+
+    match t.get_or_insert(key, InlineTable::default()) {
+        Value::InlineTable(inner) => set_in_inline_table(inner, rest, value),
+        _ => Err(anyhow!("'{}' isn't a table", key)),
+    }
+}
+
+// Keeps the spacing and trailing comment of the value being replaced
+fn replacing(old: Option<&Value>, value: Value) -> Value {
+    match old {
+        Some(old) => decorated(value, old.decor().prefix(), old.decor().suffix()),
+        None => decorated(value, " ", ""),
+    }
+}
+
+fn unset_in_table(t: &mut Table, keys: &[&str]) -> Result<bool> {
+    let (key, rest) = keys.split_first().ok_or(anyhow!("The key is empty"))?;
+    if rest.is_empty() {
+        return Ok(t.remove(key).is_some());
+    }
+
+    match t.get_mut(key) {
+        None => Ok(false),
+        Some(Item::Table(inner)) => unset_in_table(inner, rest),
+        Some(Item::Value(Value::InlineTable(inner))) => Ok(unset_in_inline_table(inner, rest)),
+        Some(_) => Err(anyhow!("'{}' isn't a table", key)),
+    }
+}
+
+fn unset_in_inline_table(t: &mut InlineTable, keys: &[&str]) -> bool {
+    match keys {
+        [key] => {
+            let removed = t.remove(key).is_some();
+            t.fmt();
+            removed
+        }
+        [key, rest @ ..] => match t.get_mut(key) {
+            Some(Value::InlineTable(inner)) => unset_in_inline_table(inner, rest),
+            _ => false,
+        },
+        [] => false,
+    }
+}
+
+/// Edits the TOML file at `path` in place, with `check` vetting the result before
+/// it's written. A missing file is treated as empty.
+pub fn edit_toml_file(
+    path: &Path,
+    edit: impl FnOnce(&mut Document) -> Result<()>,
+    check: impl FnOnce(&str) -> Result<()>,
+) -> Result<()> {
+    let current = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context(format!("Can't read path {:?}", path)),
+    };
+    let mut doc = current
+        .parse::<Document>()
+        .context(format!("Failed to parse {:?}", path))?;
+
+    edit(&mut doc)?;
+    let next = doc.to_string();
+    check(&next)?;
+
+    fs::write(path, next).context(format!("Failed to write to {:?}", path))
+}
+
+/// Writes `next` to the TOML file at `path`, changing only the keys whose values
+/// differ from `current`, so that the comments, order and formatting of the rest
+/// of the file stay as they are. An empty file is written from scratch.
+pub fn write_changes<T: Serialize>(path: &Path, current: &T, next: &T) -> Result<()> {
+    let existing = fs::read_to_string(path).unwrap_or_default();
+    if existing.trim().is_empty() {
+        let t = toml::to_string_pretty(next).context("Failed to serialize the config to TOML")?;
+        return fs::write(path, t).context(format!("Failed to write to {:?}", path));
+    }
+
+    let old = toml::Value::try_from(current).context("Failed to serialize the config to TOML")?;
+    let new = toml::Value::try_from(next).context("Failed to serialize the config to TOML")?;
+    edit_toml_file(
+        path,
+        |doc| apply_diff(doc, &mut vec![], Some(&old), Some(&new)),
+        |_| Ok(()),
+    )
+}
+
+fn apply_diff(
+    doc: &mut Document,
+    path: &mut Vec<String>,
+    old: Option<&toml::Value>,
+    new: Option<&toml::Value>,
+) -> Result<()> {
+    match (old, new) {
+        (_, None) => {
+            unset(doc, &as_keys(path))?;
+        }
+        (Some(old), Some(new)) if old == new => {}
+        (old, Some(toml::Value::Table(new)))
+            if matches!(old, Some(toml::Value::Table(_))) || !inline_new_table(path, new) =>
+        {
+            // ex: a [[jobs]] array becoming [jobs.<name>] tables
+            if matches!(old, Some(o) if !o.is_table()) {
+                unset(doc, &as_keys(path))?;
+            }
+            let old = old.and_then(|o| o.as_table());
+            let removed = old
+                .iter()
+                .flat_map(|o| o.keys())
+                .filter(|k| !new.contains_key(*k));
+            for key in new.keys().chain(removed).cloned().collect::<Vec<_>>() {
+                path.push(key.clone());
+                apply_diff(doc, path, old.and_then(|o| o.get(&key)), new.get(&key))?;
+                path.pop();
+            }
+        }
+        (_, Some(new)) => set(doc, &as_keys(path), to_edit_value(new)?)?,
+    }
+    Ok(())
+}
+
+fn as_keys(path: &[String]) -> Vec<&str> {
+    path.iter().map(String::as_str).collect()
+}
+
+// New tables of plain values below the [sections], ex: a secret's
+// `{ secret = "..", version = 1 }` in a job's env, are written inline
+fn inline_new_table(path: &[String], t: &toml::value::Table) -> bool {
+    path.len() > 2 && !t.is_empty() && t.values().all(|v| !v.is_table())
+}
+
+fn to_edit_value(value: &toml::Value) -> Result<Value> {
+    match value {
+        toml::Value::Table(t) => {
+            let mut inline = InlineTable::default();
+            for (k, v) in t {
+                inline.get_or_insert(k, to_edit_value(v)?);
+            }
+            inline.fmt();
+            Ok(Value::InlineTable(inline))
+        }
+        // Everything else is written the same inline and at the top level
+        other => other
+            .to_string()
+            .parse::<Value>()
+            .map_err(|e| anyhow!("Failed to write {} as TOML: {}", other, e)),
+    }
+}
+
+/// Fails if `keys` isn't somewhere in `config`, for catching misspelled keys that
+/// serde would otherwise ignore
+pub fn check_known_key<T: Serialize>(config: &T, keys: &[&str]) -> Result<()> {
+    let serialized =
+        toml::Value::try_from(config).context("Failed to serialize the config to TOML")?;
+    match get(&serialized, keys) {
+        Some(_) => Ok(()),
+        None => Err(anyhow!("'{}' isn't a setting brr knows", keys.join("."))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use std::fs;
+    use tempdir::TempDir;
+    use toml_edit::Document;
+
+    use super::{parse_value, set, unset};
+    use crate::config_local::{overwrite_local_config, EnvValue, SecretEnv, LOCAL_CONFIG_NAME};
+
+    #[test]
+    fn test_keeps_comments_and_order() {
+        let mut doc = "# Build Recall\n[project]\nname = \"app\" # shared with the team\n\n[jobs.build]\nrun = \"cargo build\"\nenv = { RUST_LOG = \"info\" }\n"
+            .parse::<Document>()
+            .unwrap();
+
+        set(&mut doc, &["project", "name"], parse_value("web")).unwrap();
+        set(&mut doc, &["jobs", "build", "env", "CI"], parse_value("1")).unwrap();
+        set(&mut doc, &["sync", "shallow"], parse_value("true")).unwrap();
+        assert!(unset(&mut doc, &["jobs", "build", "env", "RUST_LOG"]).unwrap());
+        assert!(!unset(&mut doc, &["containers", "default"]).unwrap());
+
+        assert_eq!(
+            doc.to_string(),
+            "# Build Recall\n[project]\nname = \"web\" # shared with the team\n\n[jobs.build]\nrun = \"cargo build\"\nenv = { CI = 1 }\n\n[sync]\nshallow = true\n"
+        );
+        assert!(set(&mut doc, &["jobs", "build"], parse_value("x")).is_err());
+    }
+
+    #[test]
+    fn test_overwriting_only_touches_what_changed() {
+        let tmp = TempDir::new(".config_edit")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let original = "# Shared with the whole team\n[project]\nname = \"app\"\n\n[jobs.build]\n# Release builds only\nrun = '''\ncargo build \\\n  --release\n'''\nenv = { TOKEN = { secret = \"token\", version = 1 } }\n";
+        fs::write(tmp.path().join(LOCAL_CONFIG_NAME), original).unwrap();
+
+        overwrite_local_config(tmp.path().to_path_buf(), |mut c| {
+            let job = c.jobs.get_mut("build").unwrap();
+            job.env.insert(
+                "TOKEN".to_string(),
+                EnvValue::AsSecret(SecretEnv {
+                    secret: "token".to_string(),
+                    version: 2,
+                }),
+            );
+            c
+        })
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(tmp.path().join(LOCAL_CONFIG_NAME)).unwrap(),
+            original.replace("version = 1", "version = 2")
+        );
+    }
+}
@@ -1,44 +1,149 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use itertools::Itertools;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    fmt::Debug,
+    fmt::{self, Debug},
     fs::{self, File, OpenOptions},
-    io::Write,
     path::PathBuf,
 };
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+use crate::{
+    config_edit::write_changes,
+    migrate::migrate_local_config,
+    resolve::resolve_local_config,
+    units::{ByteSize, TimeSpan},
+};
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
 pub struct ProjectConfig {
+    /// The project's name on Build Recall, as created by `brr init`
     pub name: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
 pub struct JobConfig {
+    /// The template in [templates] this job builds on
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    /// The script that runs the job on the farm
     pub run: String,
+    /// Paths the job produces, downloaded after it's done
     #[serde(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub artifacts: Vec<String>,
+    /// Environment variables for the job, either plain strings or secrets
     #[serde(default)]
     #[serde(skip_serializing_if = "HashMap::is_empty")]
     pub env: HashMap<String, EnvValue>,
+    /// How long a single attempt can run before the farm stops it, ex: "45m"
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<TimeSpan>,
+    /// How many more times to try the job after it fails, defaults to 0
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    /// Which failures are retried: "infra", "timeout" or an exit code, ex:
+    /// ["infra", 137]. Defaults to ["infra"].
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub retry_on: Vec<RetryOn>,
+    /// How many CPUs the job needs, a hint for the farm's scheduler
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<u32>,
+    /// How much memory the job needs, a hint for the farm's scheduler, ex: "8GB"
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<ByteSize>,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+impl JobConfig {
+    /// The failures that are retried, when there are `retries`
+    pub fn retry_on(&self) -> Vec<RetryOn> {
+        if self.retry_on.is_empty() {
+            vec![RetryOn::Failure(RetryFailure::Infra)]
+        } else {
+            self.retry_on.clone()
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RetryFailure {
+    /// The farm failed to run the job, ex: a lost machine or network
+    Infra,
+    /// An attempt ran longer than the job's `timeout`
+    Timeout,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum RetryOn {
+    Failure(RetryFailure),
+    /// The script exited with this code
+    ExitCode(i32),
+}
+
+impl fmt::Display for RetryOn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetryOn::Failure(RetryFailure::Infra) => write!(f, "infra"),
+            RetryOn::Failure(RetryFailure::Timeout) => write!(f, "timeout"),
+            RetryOn::ExitCode(code) => write!(f, "{}", code),
+        }
+    }
+}
+
+/// What jobs that extend a template have in common
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, Default)]
+#[schemars(deny_unknown_fields)]
+pub struct JobTemplate {
+    /// Another template this one builds on
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    /// Runs before the job's own `run` script, ex: to set up the toolchain
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_prefix: Option<String>,
+    /// Runs after the job's own `run` script
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_suffix: Option<String>,
+    /// Added to the artifacts of the jobs that extend it
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<String>,
+    /// Environment variables for the jobs that extend it, which can override them
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, EnvValue>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
 pub struct SecretEnv {
+    /// The name of the secret, as given to `brr secrets set`
     pub secret: String,
     pub version: i32,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
 #[serde(untagged)]
 pub enum EnvValue {
     AsSecret(SecretEnv),
     AsString(String),
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
 pub struct Container {
     /// Container image to run the build in
     pub image: String,
@@ -47,16 +152,121 @@ pub struct Container {
     pub persist: Vec<String>,
 }
 
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizeAction {
+    /// Refuse to push, unless `brr run --force`
+    Fail,
+    /// Print the offending files and push anyway
+    Warn,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubmoduleMode {
+    /// Send the submodule's files, as they are on disk
+    Materialize,
+    /// Only send the commit the submodule points to
+    Gitlink,
+    /// Leave the submodule out
+    Skip,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LfsMode {
+    /// Send the content of Git LFS pointers, from the local LFS store
+    Resolve,
+    /// Refuse to push while there are Git LFS pointers in the worktree
+    Fail,
+}
+
+pub const DEFAULT_MAX_FILE_SIZE: ByteSize = ByteSize(100 * 1024 * 1024);
+pub const DEFAULT_MAX_PUSH_SIZE: ByteSize = ByteSize(1024 * 1024 * 1024);
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, Default)]
+#[schemars(deny_unknown_fields)]
+pub struct SyncConfig {
+    /// Push each sync as a commit without parents, instead of chaining it onto
+    /// the previous sync. Only the latest tree is sent, so history stops growing.
+    #[serde(default)]
+    pub shallow: bool,
+    /// The largest single file that can be pushed, defaults to 100MB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<ByteSize>,
+    /// The most new file content a single push can send, defaults to 1GB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_push_size: Option<ByteSize>,
+    /// What to do when a push goes over one of the limits above, defaults to "fail"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_oversize: Option<OversizeAction>,
+    /// How to send each submodule or nested git repo, by path from the project
+    /// root. Those that aren't listed are materialized.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub submodules: HashMap<String, SubmoduleMode>,
+    /// What to do with Git LFS pointers in the worktree, defaults to "resolve"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lfs: Option<LfsMode>,
+}
+
+impl SyncConfig {
+    pub fn max_file_size(&self) -> ByteSize {
+        self.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE)
+    }
+
+    pub fn max_push_size(&self) -> ByteSize {
+        self.max_push_size.unwrap_or(DEFAULT_MAX_PUSH_SIZE)
+    }
+
+    pub fn on_oversize(&self) -> OversizeAction {
+        self.on_oversize.unwrap_or(OversizeAction::Fail)
+    }
+
+    pub fn lfs(&self) -> LfsMode {
+        self.lfs.unwrap_or(LfsMode::Resolve)
+    }
+
+    pub fn submodule_mode(&self, path: &str) -> SubmoduleMode {
+        self.submodules
+            .get(path.trim_end_matches('/'))
+            .copied()
+            .unwrap_or(SubmoduleMode::Materialize)
+    }
+}
+
 // What's stored in their repo directory
-#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, Default)]
+#[schemars(deny_unknown_fields)]
 pub struct LocalConfig {
+    /// The version of the buildrecall.toml format. Older files are upgraded when
+    /// they're read, and rewritten with `brr config migrate`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// Other TOML files with more [vars], [templates], [jobs] and [containers], as globs
+    /// relative to this one, ex: ["ci/jobs/*.toml"]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
     pub project: Option<ProjectConfig>,
+    /// Values for `${name}` in the jobs' `run`, `artifacts` and env
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub vars: HashMap<String, String>,
+    /// Shared parts of jobs, for them to `extends`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub templates: HashMap<String, JobTemplate>,
     #[serde(default)]
     #[serde(skip_serializing_if = "HashMap::is_empty")]
     pub jobs: HashMap<String, JobConfig>,
     #[serde(default)]
     #[serde(skip_serializing_if = "HashMap::is_empty")]
     pub containers: HashMap<String, Container>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sync: Option<SyncConfig>,
 }
 
 impl LocalConfig {
@@ -67,6 +277,10 @@ impl LocalConfig {
             .collect_vec()
     }
 
+    pub fn sync(&self) -> SyncConfig {
+        self.sync.clone().unwrap_or_default()
+    }
+
     pub fn project(&self) -> ProjectConfig {
         match self.project.clone() {
             Some(p) => p,
@@ -77,6 +291,10 @@ impl LocalConfig {
 
 pub const LOCAL_CONFIG_NAME: &str = "buildrecall.toml";
 
+/// The version of the buildrecall.toml format, bumped along with its JSON Schema
+/// whenever the format changes. Add a migration in migrate.rs along with it.
+pub const CONFIG_FORMAT_VERSION: u32 = 3;
+
 fn ensure_local_config_file(dir: PathBuf) -> Result<File> {
     fs::create_dir_all(dir.clone()).context(format!("Failed to create dir {:?}", dir.clone()))?;
     let filepath = dir.join(LOCAL_CONFIG_NAME);
@@ -85,6 +303,7 @@ fn ensure_local_config_file(dir: PathBuf) -> Result<File> {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(filepath.clone())
         .context(format!(
             "Failed to create and open config file {:?}",
@@ -94,7 +313,18 @@ fn ensure_local_config_file(dir: PathBuf) -> Result<File> {
     Ok(f)
 }
 
+/// The buildrecall.toml in `dir`, with its includes and templates applied
 pub fn read_local_config(dir: PathBuf) -> Result<LocalConfig> {
+    let (_, config) = read_local_config_as_written(dir.clone())?;
+    resolve_local_config(&dir, config).context(format!(
+        "Failed to read {:?}, run `brr validate` for the details",
+        dir.join(LOCAL_CONFIG_NAME)
+    ))
+}
+
+/// The buildrecall.toml in `dir` as it's written, along with what it says in the
+/// current format. Its includes and templates are left as they are.
+pub fn read_local_config_as_written(dir: PathBuf) -> Result<(toml::Value, LocalConfig)> {
     ensure_local_config_file(dir.clone())?;
 
     fs::create_dir_all(dir.clone())?;
@@ -102,41 +332,36 @@ pub fn read_local_config(dir: PathBuf) -> Result<LocalConfig> {
     let f = fs::read_to_string(filepath.clone())
         .context(format!("Can't read path {:?}", filepath))
         .unwrap();
-    let config: LocalConfig = toml::from_str(f.as_str()).context("Failed to parse toml")?;
+    let written: toml::Value = toml::from_str(f.as_str()).context(format!(
+        "Failed to parse {:?}, run `brr validate` for the details",
+        filepath
+    ))?;
+
+    let mut current = written.clone();
+    if let toml::Value::Table(t) = &mut current {
+        migrate_local_config(t).context(format!("Failed to read {:?}", filepath))?;
+    }
+    let config: LocalConfig = current.try_into().context(format!(
+        "Failed to parse {:?}, run `brr validate` for the details",
+        filepath
+    ))?;
 
-    Ok(config)
+    Ok((written, config))
 }
 
+/// Changes buildrecall.toml with `f`. Only the keys `f` changed are rewritten, the
+/// comments and formatting of everything else are kept. Files in an older format
+/// are upgraded along the way.
 pub fn overwrite_local_config(
     dir: PathBuf,
     f: impl FnOnce(LocalConfig) -> LocalConfig,
 ) -> Result<()> {
-    let current = read_local_config(dir.clone())?;
+    let (written, current) = read_local_config_as_written(dir.clone())?;
     let next_config = f(current);
 
-    let t = match toml::to_string_pretty(&next_config) {
-        Ok(str) => str,
-        Err(e) => {
-            return Err(anyhow!(
-                "Failed to serialize the global config to TOML. Got error: '{}'",
-                e.to_string()
-            ))
-        }
-    };
-
-    fs::create_dir_all(dir.clone())?;
     let filepath = dir.join(LOCAL_CONFIG_NAME);
-
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(filepath.clone())
-        .context(format!("Failed to open config file {:?}", filepath.clone()))?;
-
-    file.write_all(t.as_bytes())
-        .context(format!("Failed to write to config file {:?}", filepath))?;
-
-    Ok(())
+    let next =
+        toml::Value::try_from(&next_config).context("Failed to serialize the config to TOML")?;
+    write_changes(&filepath, &written, &next)
+        .context(format!("Failed to write to config file {:?}", filepath))
 }
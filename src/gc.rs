@@ -0,0 +1,530 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Clap;
+use git2::{Buf, Oid, Repository};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    config_local::{LocalConfig, LOCAL_CONFIG_NAME},
+    git::{self, ACKED_REF},
+    lock::{RepoLock, DEFAULT_LOCK_TIMEOUT},
+    units::format_bytes,
+};
+
+/// How many syncs `brr gc` keeps in each shadow repo by default
+pub const DEFAULT_KEEP: usize = 20;
+
+/// Shadow repos bigger than this are collected automatically after a push
+pub const AUTO_GC_THRESHOLD: u64 = 1024 * 1024 * 1024;
+
+// Objects younger than this are never deleted, since a push that started
+// before the gc may still be reading them
+const PRUNE_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Prunes old history from the shadow git repos (in ~/.cache/buildrecall/.gits),
+/// repacks them, and deletes the ones whose project is gone
+#[derive(Clap, Debug)]
+pub struct Gc {
+    /// How many of the most recent syncs to keep in each shadow repo
+    #[clap(long, default_value = "20")]
+    pub keep: usize,
+}
+
+pub struct GcOutcome {
+    pub removed: bool,
+    pub dropped_commits: usize,
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+pub async fn run_gc(global_config_dir: PathBuf, args: Gc) -> Result<()> {
+    if args.keep == 0 {
+        return Err(anyhow!("--keep must be at least 1"));
+    }
+
+    let dir = git::shadow_repos_dir(global_config_dir.clone());
+    if !dir.is_dir() {
+        eprintln!("Nothing to collect in {:?}", dir);
+        return Ok(());
+    }
+
+    let mut freed = 0;
+    let mut failed = vec![];
+    for entry in fs::read_dir(&dir).context(format!("Failed to read dir {:?}", dir))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            // Lock files live next to the repos
+            continue;
+        }
+        let slug = match entry.file_name().to_str() {
+            Some(s) => s.to_string(),
+            None => continue,
+        };
+
+        // One broken or busy repo shouldn't keep the others from being collected
+        let outcome = match gc_project(global_config_dir.clone(), slug.clone(), args.keep).await {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                eprintln!("{}: failed to collect its shadow repo: {:#}", slug, e);
+                failed.push(slug);
+                continue;
+            }
+        };
+        freed += outcome.size_before.saturating_sub(outcome.size_after);
+
+        if outcome.removed {
+            eprintln!(
+                "{}: removed, its buildrecall.toml is gone ({})",
+                slug,
+                format_bytes(outcome.size_before)
+            );
+        } else {
+            eprintln!(
+                "{}: dropped {} old syncs, {} -> {}",
+                slug,
+                outcome.dropped_commits,
+                format_bytes(outcome.size_before),
+                format_bytes(outcome.size_after)
+            );
+        }
+    }
+
+    eprintln!("Freed {}", format_bytes(freed));
+
+    if !failed.is_empty() {
+        return Err(anyhow!(
+            "Failed to collect the shadow repos for {}",
+            failed
+                .iter()
+                .map(|slug| format!("'{}'", slug))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Runs `gc_project` if the project's shadow repo has grown past `AUTO_GC_THRESHOLD`.
+pub async fn auto_gc(global_config_dir: PathBuf, slug: String) -> Result<()> {
+    let path = git::shadow_repos_dir(global_config_dir.clone()).join(slug.clone());
+    let size = dir_size(&path);
+    if size < AUTO_GC_THRESHOLD {
+        return Ok(());
+    }
+
+    eprintln!(
+        "The shadow git repo for '{}' is {}, collecting it (see `brr gc`)",
+        slug,
+        format_bytes(size)
+    );
+    gc_project(global_config_dir, slug, DEFAULT_KEEP).await?;
+
+    Ok(())
+}
+
+pub async fn gc_project(
+    global_config_dir: PathBuf,
+    slug: String,
+    keep: usize,
+) -> Result<GcOutcome> {
+    // Only the shadow repo and its lock are needed, not a working connection config
+    let path = git::shadow_repos_dir(global_config_dir).join(slug.clone());
+
+    let _lock = RepoLock::acquire_async(&path, DEFAULT_LOCK_TIMEOUT).await?;
+    let size_before = dir_size(&path);
+
+    let repo = Repository::open_bare(&path).context(format!("Failed to open repo {:?}", path))?;
+
+    if is_orphaned(&repo, &slug) {
+        drop(repo);
+        fs::remove_dir_all(&path).context(format!("Failed to delete {:?}", path))?;
+        return Ok(GcOutcome {
+            removed: true,
+            dropped_commits: 0,
+            size_before,
+            size_after: 0,
+        });
+    }
+
+    let dropped_commits = truncate_history(&repo, keep).context("Failed to truncate history")?;
+    repack(&repo, PRUNE_GRACE_PERIOD).context("Failed to repack")?;
+
+    Ok(GcOutcome {
+        removed: false,
+        dropped_commits,
+        size_before,
+        size_after: dir_size(&path),
+    })
+}
+
+// A shadow repo is orphaned when the folder it was synced from no longer has a
+// buildrecall.toml for that project. Repos that never recorded a folder are kept.
+fn is_orphaned(repo: &Repository, slug: &str) -> bool {
+    let worktree = match git::recorded_worktree(repo) {
+        Some(w) => w,
+        None => return false,
+    };
+
+    let contents = match fs::read_to_string(worktree.join(LOCAL_CONFIG_NAME)) {
+        Ok(c) => c,
+        Err(_) => return true,
+    };
+
+    match toml::from_str::<LocalConfig>(&contents) {
+        Ok(config) => config.project().name.as_deref() != Some(slug),
+        // Don't throw away someone's repo because of a typo in their config
+        Err(_) => false,
+    }
+}
+
+// Rewrites the newest `keep` commits on HEAD so that the oldest of them has no
+// parent, and keeps only the newest `keep` sync refs. Shallow syncs are commits
+// without parents, so their refs have to be pruned even when HEAD is short.
+// Returns how many syncs were dropped.
+fn truncate_history(repo: &Repository, keep: usize) -> Result<usize> {
+    let head = match repo.head().ok() {
+        Some(h) => h,
+        None => return Ok(0),
+    };
+    let head_name = head
+        .name()
+        .ok_or(anyhow!("HEAD is not valid utf-8"))?
+        .to_string();
+
+    let mut chain = vec![];
+    let mut next = head.peel_to_commit().ok();
+    while let Some(commit) = next {
+        next = commit.parent(0).ok();
+        chain.push(commit);
+    }
+
+    // Where each sync is on HEAD, to order syncs made within the same second
+    let position: HashMap<Oid, usize> =
+        chain.iter().enumerate().map(|(i, c)| (c.id(), i)).collect();
+
+    let mut dropped = 0;
+    let mut rewritten: HashMap<Oid, Oid> = HashMap::new();
+    let mut dropped_from_head: HashSet<Oid> = HashSet::new();
+    if chain.len() > keep {
+        dropped = chain.len() - keep;
+        dropped_from_head = chain[keep..].iter().map(|c| c.id()).collect();
+        chain.truncate(keep);
+
+        let mut parent: Option<git2::Commit> = None;
+        for commit in chain.iter().rev() {
+            let parents: Vec<&git2::Commit> = parent.iter().collect();
+            let oid = repo.commit(
+                None,
+                &commit.author(),
+                &commit.committer(),
+                commit.message().unwrap_or_default(),
+                &commit.tree()?,
+                &parents,
+            )?;
+            rewritten.insert(commit.id(), oid);
+            parent = Some(repo.find_commit(oid)?);
+        }
+
+        if let Some(new_head) = parent {
+            repo.reference(&head_name, new_head.id(), true, "brr gc")?;
+        }
+    }
+
+    let mut sync_refs = vec![];
+    for r in repo.references_glob("refs/brr/*")? {
+        let r = r?;
+        if let (Some(name), Some(target)) = (r.name(), r.target()) {
+            // The farm has this one, keeping it means its objects don't get sent again
+            if name == ACKED_REF {
+                continue;
+            }
+            let time = repo.find_commit(target).map(|c| c.time().seconds())?;
+            let order = (
+                std::cmp::Reverse(time),
+                position.get(&target).copied().unwrap_or(usize::MAX),
+            );
+            sync_refs.push((name.to_string(), target, order));
+        }
+    }
+    // Newest first
+    sync_refs.sort_by_key(|r| r.2);
+
+    for (i, (name, target, _)) in sync_refs.into_iter().enumerate() {
+        if i >= keep || dropped_from_head.contains(&target) {
+            repo.find_reference(&name)?.delete()?;
+            if !dropped_from_head.contains(&target) {
+                dropped += 1;
+            }
+        } else if let Some(new_target) = rewritten.get(&target) {
+            repo.reference(&name, *new_target, true, "brr gc")?;
+        }
+    }
+
+    Ok(dropped)
+}
+
+// Writes everything reachable from HEAD and the sync refs into a single new pack,
+// then deletes the loose objects and old packs that are older than `grace`.
+// libgit2 can't gc on its own.
+pub(crate) fn repack(repo: &Repository, grace: Duration) -> Result<()> {
+    let mut walk = repo.revwalk()?;
+    if walk.push_head().is_err() {
+        // Nothing has been synced yet
+        return Ok(());
+    }
+    walk.push_glob("refs/brr/*")?;
+
+    let mut builder = repo.packbuilder()?;
+    builder.insert_walk(&mut walk)?;
+
+    // The next sync trusts the index to know which files are already written, so
+    // what it points to is kept even if no sync ref does, such as the files of a
+    // `brr run --dry-run` or of a push that failed
+    let mut index = repo.index()?;
+    if !index.is_empty() {
+        let tree = index
+            .write_tree()
+            .context("Failed to read the shadow git repo's index")?;
+        builder.insert_tree(tree)?;
+    }
+    if builder.object_count() == 0 {
+        return Ok(());
+    }
+
+    let mut buf = Buf::new();
+    builder.write_buf(&mut buf)?;
+
+    let objects_dir = repo.path().join("objects");
+    let old_packs = list_packs(&objects_dir)?;
+
+    let odb = repo.odb()?;
+    let mut writer = odb.packwriter()?;
+    writer
+        .write_all(&buf)
+        .context("Failed to write the new pack")?;
+    writer.commit()?;
+
+    let cutoff = SystemTime::now() - grace;
+    let is_old = |p: &Path| {
+        p.metadata()
+            .and_then(|m| m.modified())
+            .map(|t| t < cutoff)
+            .unwrap_or(false)
+    };
+
+    for pack in old_packs {
+        if is_old(&pack) {
+            let _ = fs::remove_file(&pack);
+        }
+    }
+
+    for entry in fs::read_dir(&objects_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_loose_dir = name
+            .to_str()
+            .map(|n| n.len() == 2 && n.chars().all(|c| c.is_ascii_hexdigit()))
+            .unwrap_or(false);
+        if !is_loose_dir {
+            continue;
+        }
+
+        for object in fs::read_dir(entry.path())? {
+            let object = object?.path();
+            if is_old(&object) {
+                let _ = fs::remove_file(&object);
+            }
+        }
+        let _ = fs::remove_dir(entry.path());
+    }
+
+    Ok(())
+}
+
+fn list_packs(objects_dir: &Path) -> Result<HashSet<PathBuf>> {
+    let pack_dir = objects_dir.join("pack");
+    if !pack_dir.is_dir() {
+        return Ok(HashSet::new());
+    }
+
+    let mut packs = HashSet::new();
+    for entry in fs::read_dir(&pack_dir)? {
+        let path = entry?.path();
+        let is_pack = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.starts_with("pack-"))
+            .unwrap_or(false);
+        if is_pack {
+            packs.insert(path);
+        }
+    }
+
+    Ok(packs)
+}
+
+pub fn dir_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use git2::Repository;
+    use std::fs;
+    use tempdir::TempDir;
+
+    use super::{gc_project, run_gc, Gc};
+    use crate::git::shadow_repos_dir;
+
+    fn make_syncs(repo: &Repository, count: usize) {
+        let sig = git2::Signature::now("test", "test").unwrap();
+        for i in 0..count {
+            let mut builder = repo.treebuilder(None).unwrap();
+            let blob = repo.blob(format!("sync {}", i).as_bytes()).unwrap();
+            builder.insert("file", blob, 0o100644).unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+
+            let head = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+            let parents: Vec<&git2::Commit> = head.iter().collect();
+            let oid = repo
+                .commit(Some("HEAD"), &sig, &sig, "sync", &tree, &parents)
+                .unwrap();
+            repo.reference(&format!("refs/brr/{}", tree.id()), oid, true, "test")
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_truncates_history_and_sync_refs() {
+        let tmp = TempDir::new(".buildrecall")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let dir = tmp.path().to_path_buf();
+        let repo = Repository::init_bare(shadow_repos_dir(dir.clone()).join("proj")).unwrap();
+        make_syncs(&repo, 5);
+
+        let outcome = gc_project(dir, "proj".to_string(), 2).await.unwrap();
+        assert!(!outcome.removed);
+        assert_eq!(outcome.dropped_commits, 3);
+
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.parent_count(), 1);
+        assert_eq!(head.parent(0).unwrap().parent_count(), 0);
+
+        let sync_refs = repo.references_glob("refs/brr/*").unwrap().count();
+        assert_eq!(sync_refs, 2);
+    }
+
+    #[tokio::test]
+    async fn test_prunes_the_sync_refs_of_shallow_syncs() {
+        let tmp = TempDir::new(".buildrecall")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let dir = tmp.path().to_path_buf();
+        let repo = Repository::init_bare(shadow_repos_dir(dir.clone()).join("proj")).unwrap();
+
+        // Each shallow sync is a commit of its own, HEAD never grows
+        let mut refs = vec![];
+        for i in 0..5 {
+            let mut builder = repo.treebuilder(None).unwrap();
+            let blob = repo.blob(format!("sync {}", i).as_bytes()).unwrap();
+            builder.insert("file", blob, 0o100644).unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+            let when = git2::Signature::new("test", "test", &git2::Time::new(1_600_000_000 + i, 0))
+                .unwrap();
+            let oid = repo.commit(None, &when, &when, "sync", &tree, &[]).unwrap();
+            repo.set_head_detached(oid).unwrap();
+            let name = format!("refs/brr/{}", tree.id());
+            repo.reference(&name, oid, true, "test").unwrap();
+            refs.push(name);
+        }
+
+        let outcome = gc_project(dir, "proj".to_string(), 2).await.unwrap();
+        assert_eq!(outcome.dropped_commits, 3);
+
+        let mut left: Vec<String> = repo
+            .references_glob("refs/brr/*")
+            .unwrap()
+            .map(|r| r.unwrap().name().unwrap().to_string())
+            .collect();
+        left.sort();
+        let mut newest = refs[3..].to_vec();
+        newest.sort();
+        assert_eq!(left, newest);
+    }
+
+    #[tokio::test]
+    async fn test_removes_repos_whose_project_is_gone() {
+        let tmp = TempDir::new(".buildrecall")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let dir = tmp.path().to_path_buf();
+        let worktree = tmp.path().join("worktree");
+        fs::create_dir_all(&worktree).unwrap();
+
+        let path = shadow_repos_dir(dir.clone()).join("proj");
+        let repo = Repository::init_bare(&path).unwrap();
+        repo.config()
+            .unwrap()
+            .set_str("brr.worktree", worktree.to_str().unwrap())
+            .unwrap();
+
+        // Still there while buildrecall.toml points at this project
+        fs::write(
+            worktree.join("buildrecall.toml"),
+            "[project]\nname = 'proj'\n",
+        )
+        .unwrap();
+        assert!(
+            !gc_project(dir.clone(), "proj".to_string(), 2)
+                .await
+                .unwrap()
+                .removed
+        );
+
+        fs::write(
+            worktree.join("buildrecall.toml"),
+            "[project]\nname = 'other'\n",
+        )
+        .unwrap();
+        assert!(
+            gc_project(dir, "proj".to_string(), 2)
+                .await
+                .unwrap()
+                .removed
+        );
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_keeps_going_past_a_broken_repo() {
+        let tmp = TempDir::new(".buildrecall")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let dir = tmp.path().to_path_buf();
+        // gc doesn't need a config it can connect with
+        fs::write(dir.join("config.toml"), "not toml at all [").unwrap();
+
+        let repos = shadow_repos_dir(dir.clone());
+        fs::create_dir_all(repos.join("broken")).unwrap();
+        let repo = Repository::init_bare(repos.join("proj")).unwrap();
+        make_syncs(&repo, 5);
+
+        let err = run_gc(dir, Gc { keep: 2 }).await.err().unwrap();
+        assert!(err.to_string().contains("'broken'"));
+        assert_eq!(repo.references_glob("refs/brr/*").unwrap().count(), 2);
+    }
+}
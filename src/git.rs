@@ -1,31 +1,214 @@
 use anyhow::{anyhow, Context, Result};
-use git2::{IndexAddOption, Oid, PushOptions, RemoteCallbacks, Repository};
+use git2::{
+    IndexAddOption, ObjectType, Oid, PushOptions, RemoteCallbacks, Repository, TreeWalkMode,
+    TreeWalkResult,
+};
 use hyper::{
-    header::{AUTHORIZATION, UPGRADE},
-    http::uri::Scheme,
-    Body, Client, StatusCode,
+    header::{AUTHORIZATION, CONTENT_TYPE, HOST, UPGRADE},
+    http::uri::{Authority, Scheme},
+    upgrade::Upgraded,
+    Body, StatusCode,
 };
+use once_cell::sync::Lazy;
 use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
     convert::TryFrom,
     env,
     path::{Path, PathBuf},
-    sync::Once,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Once,
+    },
+    time::Duration,
 };
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::*;
 
-use crate::{api::PushQueryParams, config_global::get_global_config_dir, config_local::read_local_config, run::JobArgs};
-use crate::config_global::read_global_config;
-
+use crate::{
+    bridge::BlockingStream,
+    config_global::{
+        get_global_cache_dir, read_global_config, GlobalConfig, SHADOW_REPOS_DIR_NAME,
+    },
+    config_local::{read_local_config, SyncConfig},
+    guard, lfs,
+    lock::{RepoLock, DEFAULT_LOCK_TIMEOUT},
+    net::NetworkConfig,
+    progress::{PushProgress, PushSummary},
+    run::{push_params, JobArgs},
+    submodules,
+};
 
-pub fn worktree_path(slug: String) -> Result<PathBuf> {
+pub fn worktree_path(_slug: String) -> Result<PathBuf> {
     // TODO: make this work anywhere in the repo, and use the buildrecall.toml or .git to figure out
     // where we are
     Ok(env::current_dir()?)
 }
 
+/// Where the shadow git repos live, one per project slug
+pub fn shadow_repos_dir(global_config_dir: PathBuf) -> PathBuf {
+    get_global_cache_dir(&global_config_dir).join(SHADOW_REPOS_DIR_NAME)
+}
+
 fn repo_path(global_config_dir: PathBuf, slug: String) -> Result<PathBuf> {
-    Ok(global_config_dir.join(".gits").join(slug))
+    Ok(shadow_repos_dir(global_config_dir).join(slug))
+}
+
+// Remembered so that `brr gc` can tell when a shadow repo's project has gone away
+const WORKTREE_CONFIG_KEY: &str = "brr.worktree";
+
+/// The folder a shadow repo was last synced from, if it's been recorded
+pub fn recorded_worktree(repo: &Repository) -> Option<PathBuf> {
+    repo.config().ok()?.get_path(WORKTREE_CONFIG_KEY).ok()
+}
+
+fn record_worktree(repo: &Repository, worktree: &Path) -> Result<()> {
+    if recorded_worktree(repo).as_deref() == Some(worktree) {
+        return Ok(());
+    }
+
+    let as_str = worktree
+        .to_str()
+        .ok_or(anyhow!("Failed to convert {:?} to string", worktree))?;
+    repo.config()
+        .context("Failed to open the shadow git repo's config")?
+        .set_str(WORKTREE_CONFIG_KEY, as_str)
+        .context("Failed to record the worktree in the shadow git repo's config")
+}
+
+// Each sync is committed to its own ref, so that concurrent runs never push each other's trees
+fn sync_ref_name(tree_hash: Oid) -> String {
+    format!("refs/brr/{}", tree_hash)
+}
+
+/// Points at the last commit the farm accepted. Kept around (and kept by `brr gc`)
+/// so that its objects don't have to be sent again.
+pub const ACKED_REF: &str = "refs/brr/acked";
+
+// Moves HEAD (or the branch it points to) without requiring the new commit to
+// have the current tip as a parent, which shallow syncs don't
+fn update_head(repo: &Repository, oid: Oid) -> Result<(), git2::Error> {
+    let head = repo.find_reference("HEAD")?;
+    let name = head.symbolic_target().unwrap_or("HEAD").to_string();
+    repo.reference(&name, oid, true, "sync with buildrecall")?;
+    Ok(())
+}
+
+// Build scripts have to stay executable on the farm, and symlinks have to stay
+// links. libgit2 guesses both settings from the filesystem when a repo is created.
+fn track_modes_and_symlinks(repo: &Repository) -> Result<()> {
+    if cfg!(unix) {
+        let mut config = repo
+            .config()
+            .context("Failed to open the shadow git repo's config")?;
+        for key in &["core.filemode", "core.symlinks"] {
+            if config.get_bool(key).ok() != Some(true) {
+                config.set_bool(key, true)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// The index outlives each sync, and add_all only adds, so files deleted from the
+// worktree since the last sync would still be pushed
+fn unstage_deleted_files(index: &mut git2::Index) -> Result<()> {
+    index
+        .update_all(["*"].iter(), None)
+        .context("Failed to remove deleted files from the shadow git repo")
+}
+
+// add_all keeps updating files that are already staged, even once they're ignored.
+// Without this, files just added to .brrignore (see guard::check_push_size) would
+// still be pushed.
+fn unstage_ignored_files(repo: &Repository, index: &mut git2::Index) -> Result<()> {
+    index
+        .remove_all(
+            ["*"].iter(),
+            Some(&mut |path: &Path, _: &[u8]| {
+                match repo.is_path_ignored(path) {
+                    Ok(true) => 0, // remove
+                    _ => 1,        // keep
+                }
+            }),
+        )
+        .context("Failed to remove ignored files from the shadow git repo")
+}
+
+/// A tree written by `write_worktree_tree`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncedTree {
+    pub tree: Oid,
+    /// The commit of each submodule or nested repo whose files are in `tree`, by path
+    pub submodules: BTreeMap<String, Oid>,
+    /// What builds of the tree are keyed on: `tree` itself, or with materialized
+    /// submodules, `tree` along with their commits, since the files alone don't say
+    /// which commit a submodule is at
+    pub hash: Oid,
+}
+
+impl SyncedTree {
+    fn new(tree: Oid, commits: BTreeMap<String, Oid>) -> Result<SyncedTree> {
+        let hash = if commits.is_empty() {
+            tree
+        } else {
+            let identity = format!("{}\n{}", tree, submodules::manifest(&commits));
+            Oid::hash_object(ObjectType::Blob, identity.as_bytes())?
+        };
+        Ok(SyncedTree {
+            tree,
+            submodules: commits,
+            hash,
+        })
+    }
+}
+
+/// Stages the worktree into the shadow repo's index and writes it out as a tree,
+/// with submodules and nested repos materialized according to `sync`.
+/// Callers must hold the repo's `RepoLock`, see `RecallGit::lock_project`.
+pub(crate) fn write_worktree_tree(repo: &Repository, sync: &SyncConfig) -> Result<SyncedTree> {
+    let worktree = repo
+        .workdir()
+        .ok_or(anyhow!("The shadow git repo has no workdir"))?
+        .to_path_buf();
+    let mut i = repo.index().context("Failed to get a git index")?;
+
+    // libgit2 can't add a nested repo, they're staged as gitlinks below instead
+    let mut nested = vec![];
+    i.add_all(
+        ["*"].iter(),
+        IndexAddOption::DEFAULT,
+        Some(&mut |path: &Path, _: &[u8]| {
+            if submodules::is_nested_repo(&worktree, path) {
+                nested.push(path.to_path_buf());
+                1 // skip
+            } else {
+                0 // add
+            }
+        }),
+    )
+    .context("Failed to stage changes in shadow git repo (required to compute git hash)")?;
+    submodules::stage_gitlinks(&mut i, &worktree, &nested)?;
+
+    unstage_deleted_files(&mut i)?;
+    unstage_ignored_files(repo, &mut i)?;
+
+    // Saving the index lets the next sync skip re-hashing files that haven't changed
+    i.write()
+        .context("Failed to save the shadow git repo's index")?;
+
+    let tree_oid = i.write_tree().context("Failed to hash git repository")?;
+
+    // Submodules stay gitlinks in the index, they're only swapped for their files in
+    // the tree, which keeps the index stable from one sync to the next
+    let (tree_oid, commits) = if submodules::has_gitlinks(&i) {
+        submodules::materialize(repo, tree_oid, &worktree, sync)?
+    } else {
+        (tree_oid, BTreeMap::new())
+    };
+
+    // Same for LFS pointers, so the tree has the same hash whether or not they've been pulled
+    let tree_oid = lfs::resolve_pointers(repo, tree_oid, &worktree, sync)?;
+    SyncedTree::new(tree_oid, commits)
 }
 
 const RECALL_GIT_SCHEME_HTTP: &str = "recall+git";
@@ -35,46 +218,175 @@ fn init_git_transport() {
     static INIT: Once = Once::new();
 
     INIT.call_once(move || unsafe {
-        git2::transport::register(RECALL_GIT_SCHEME_HTTP, |remote| {
-            git2::transport::Transport::smart(remote, false, RecallGitTransport)
+        for (name, scheme) in &[
+            (RECALL_GIT_SCHEME_HTTP, Scheme::HTTP),
+            (RECALL_GIT_SCHEME_HTTPS, Scheme::HTTPS),
+        ] {
+            git2::transport::register(name, move |remote| {
+                let url = remote.url().unwrap_or_default();
+                let transport = RecallGitTransport {
+                    scheme: scheme.clone(),
+                    context: lookup_connection(url)?,
+                    body: lookup_push_body(url),
+                };
+                git2::transport::Transport::smart(remote, false, transport)
+            })
+            .context("Failed to register the git transport")
+            .unwrap();
+        }
+    });
+}
+
+/// Who the git transport connects to, and as whom
+#[derive(Clone, Debug)]
+pub struct ConnectionContext {
+    /// ex: "recalls+git://scheduler.buildrecall.com"
+    pub git_host: String,
+    pub access_token: Option<String>,
+    /// How long connecting, and each read or write, can take
+    pub timeout: Duration,
+    pub network: NetworkConfig,
+}
+
+impl ConnectionContext {
+    pub fn from_config(config: &GlobalConfig) -> Result<ConnectionContext> {
+        Ok(ConnectionContext {
+            git_host: config.git_host(),
+            access_token: config.access_token(),
+            timeout: config.timeout(),
+            network: NetworkConfig::from_config(config)?,
         })
-        .context("Failed to register the git transport")
-        .unwrap();
-        git2::transport::register(RECALL_GIT_SCHEME_HTTPS, |remote| {
-            git2::transport::Transport::smart(remote, false, RecallsGitTransport)
+    }
+}
+
+// libgit2 transports are registered once for the whole process, so each `RecallGit`
+// registers its connection here and puts the ID in its remote URLs' username.
+// That's what lets several accounts push from the same process.
+static CONNECTIONS: Lazy<Mutex<HashMap<u64, Arc<ConnectionContext>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// What a push sends in its request body, by the ID of the connection registered
+// for that push, see `PushConnection`
+static PUSH_BODIES: Lazy<Mutex<HashMap<u64, Arc<Vec<u8>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn register_connection(context: ConnectionContext) -> u64 {
+    let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    CONNECTIONS.lock().unwrap().insert(id, Arc::new(context));
+    id
+}
+
+fn connection_id(url: &str) -> Option<u64> {
+    url::Url::parse(url)
+        .ok()
+        .and_then(|u| u.username().parse::<u64>().ok())
+}
+
+fn connection_url(id: u64, path_and_query: &str) -> Result<String> {
+    let host = CONNECTIONS
+        .lock()
+        .unwrap()
+        .get(&id)
+        .map(|c| c.git_host.clone())
+        .ok_or(anyhow!("This connection has been closed"))?;
+    let (scheme, rest) = host
+        .split_once("://")
+        .ok_or(anyhow!("'{}' is not a git host URL", host))?;
+
+    Ok(format!("{}://{}@{}{}", scheme, id, rest, path_and_query))
+}
+
+fn lookup_push_body(url: &str) -> Option<Arc<Vec<u8>>> {
+    let id = connection_id(url)?;
+    PUSH_BODIES.lock().unwrap().get(&id).cloned()
+}
+
+fn lookup_connection(url: &str) -> Result<Arc<ConnectionContext>, git2::Error> {
+    use git2::{ErrorClass, ErrorCode};
+
+    let id = connection_id(url).ok_or_else(|| {
+        transport_error(
+            ErrorCode::Invalid,
+            ErrorClass::Net,
+            format!("{} isn't a remote created by brr", url),
+        )
+    })?;
+
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| {
+            transport_error(
+                ErrorCode::NotFound,
+                ErrorClass::Net,
+                format!("No connection is registered for {}", url),
+            )
         })
-        .context("Failed to register the git transport")
-        .unwrap();
-    });
 }
 
 pub struct RecallGit {
     global_config_dir: PathBuf,
+    connection_id: u64,
 }
 
 impl RecallGit {
+    /// Connects with the host and credentials in the global config
     pub fn new(global_config_dir: PathBuf) -> Result<RecallGit> {
+        let config = read_global_config(global_config_dir.clone())?;
+        Ok(RecallGit::with_connection(
+            global_config_dir,
+            ConnectionContext::from_config(&config)?,
+        ))
+    }
+
+    pub fn with_connection(global_config_dir: PathBuf, context: ConnectionContext) -> RecallGit {
         let _ = tracing_subscriber::fmt::try_init();
         init_git_transport();
 
-        Ok(RecallGit {
-            global_config_dir: global_config_dir,
-        })
+        RecallGit {
+            global_config_dir,
+            connection_id: register_connection(context),
+        }
+    }
+
+    /// A URL on the git host that's routed through this `RecallGit`'s connection,
+    /// `path_and_query` starts with a slash
+    pub fn remote_url(&self, path_and_query: &str) -> Result<String> {
+        connection_url(self.connection_id, path_and_query)
     }
 
-    pub fn create_shadow_git_folder(&self, slug: String) -> Result<()> {
+    /// Takes the inter-process lock on a project's shadow repo. Hold it while
+    /// creating the repo or writing to its index or refs.
+    pub async fn lock_project(&self, slug: String) -> Result<RepoLock> {
+        let path = repo_path(self.global_config_dir.clone(), slug)?;
+        RepoLock::acquire_async(&path, DEFAULT_LOCK_TIMEOUT).await
+    }
+
+    pub async fn create_shadow_git_folder(&self, slug: String) -> Result<()> {
+        let _lock = self.lock_project(slug.clone()).await?;
+
         // Create the .git
-        let new_path = repo_path(self.global_config_dir.clone(), slug)?;
+        let new_path = repo_path(self.global_config_dir.clone(), slug.clone())?;
         std::fs::create_dir_all(&new_path)?;
-        git2::Repository::init_bare(new_path)?;
+        let repo = git2::Repository::init_bare(new_path)?;
+        track_modes_and_symlinks(&repo)?;
+        record_worktree(&repo, &worktree_path(slug)?)?;
 
         Ok(())
     }
 
-    pub fn get_repo_by_project(&self, slug: String) -> Result<Repository> {
+    pub async fn get_repo_by_project(&self, slug: String) -> Result<Repository> {
+        self.open_repo(slug.clone(), &worktree_path(slug)?).await
+    }
+
+    async fn open_repo(&self, slug: String, worktree: &Path) -> Result<Repository> {
         let dot_git_path = repo_path(self.global_config_dir.clone(), slug.clone())
             .context("Failed to create path")?;
 
+        let _lock = self.lock_project(slug.clone()).await?;
         let repo_exists = Path::new(&dot_git_path).is_dir();
         let repo = match repo_exists {
             true => git2::Repository::open_bare(&dot_git_path)
@@ -84,100 +396,158 @@ impl RecallGit {
             }
         }
         .context("Failed to init or open the shadow git repo")?;
+        track_modes_and_symlinks(&repo)?;
 
-        repo.set_workdir(&worktree_path(slug.clone())?, false)
+        repo.set_workdir(worktree, false)
             .context("Failed to create a workdir for the shadow git repo")?;
+        record_worktree(&repo, worktree)?;
+        guard::load_brrignore(&repo, worktree)?;
 
         Ok(repo)
     }
 
-    pub async fn hash_folder(&self, slug: String) -> Result<Oid> {
+    pub async fn hash_folder(&self, slug: String) -> Result<SyncedTree> {
+        let sync = read_local_config(worktree_path(slug.clone())?)?.sync();
         let repo = self
-            .get_repo_by_project(slug)
+            .get_repo_by_project(slug.clone())
+            .await
             .context("Failed to get git repository")?;
 
-        let mut i = repo.index().context("Failed to get a git index")?;
+        let _lock = self.lock_project(slug).await?;
+        write_worktree_tree(&repo, &sync)
+    }
 
-        let hash = {
-            i.add_all(["*"].iter(), IndexAddOption::DEFAULT, None)
-                .context(
-                    "Failed to stage changes in shadow git repo (required to compute git hash)",
-                )?;
-            i.write_tree()
-        }
-        .context("Failed to hash git repository")?;
+    /// The paths of the files in a tree written by `hash_folder` or `push_project`
+    pub async fn tree_files(&self, slug: String, tree_oid: Oid) -> Result<Vec<String>> {
+        let repo = self
+            .get_repo_by_project(slug)
+            .await
+            .context("Failed to get git repository")?;
+        let tree = repo
+            .find_tree(tree_oid)
+            .context("Failed to find a git tree in this repository")?;
+
+        let mut files = vec![];
+        tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() != Some(ObjectType::Tree) {
+                files.push(format!("{}{}", root, entry.name().unwrap_or_default()));
+            }
+            TreeWalkResult::Ok
+        })?;
 
-        Ok(hash)
+        Ok(files)
     }
 
-    pub async fn push_project(&self, slug: String, retry: bool, args: JobArgs) -> Result<()> {
-        let config = read_global_config(self.global_config_dir.clone())?;
-
-        let local_config = read_local_config(worktree_path(slug.clone())?)?;
+    pub async fn push_project(
+        &self,
+        slug: String,
+        retry: bool,
+        args: JobArgs,
+    ) -> Result<PushSummary> {
+        let worktree = worktree_path(slug.clone())?;
+        self.push_worktree(slug, worktree, retry, args).await
+    }
 
-        let image = match local_config.containers.get(&args.container){
-            Some(c) => c.image.clone(),
-            None => anyhow::bail!("no image configured for container {}", args.container),
-        };
+    async fn push_worktree(
+        &self,
+        slug: String,
+        worktree: PathBuf,
+        retry: bool,
+        args: JobArgs,
+    ) -> Result<PushSummary> {
+        let connection_id = self.connection_id;
+        let local_config = read_local_config(worktree.clone())?;
+        let sync = local_config.sync();
+        let shallow = sync.shallow;
 
         let repo = self
-            .get_repo_by_project(slug.clone())
+            .open_repo(slug.clone(), &worktree)
+            .await
             .context("Failed to get git repository")?;
 
+        let lock_path = repo_path(self.global_config_dir.clone(), slug.clone())?;
+
         let handle = tokio::runtime::Handle::current();
 
-        Ok(handle
+        handle
             .spawn_blocking(move || -> Result<_> {
-                let mut i = repo.index().context("Failed to get a git index")?;
-
-                let tree_oid = {
-                    i.add_all(["*"].iter(), IndexAddOption::DEFAULT, None)
-                        .context("Failed to index add all")?;
-                    i.write_tree()
+                let stage = || -> Result<SyncedTree> {
+                    let _lock = RepoLock::acquire(&lock_path, DEFAULT_LOCK_TIMEOUT)?;
+                    write_worktree_tree(&repo, &sync).context("failed to generate a git tree")
+                };
+
+                let mut synced = stage()?;
+                if !args.force && guard::check_push_size(&repo, synced.tree, &sync, &worktree, true)? {
+                    // Some files were just added to .brrignore
+                    synced = stage()?;
+                    guard::check_push_size(&repo, synced.tree, &sync, &worktree, false)?;
                 }
-                .context("failed to generate a git tree")?;
-
-                let tree = repo
-                    .find_tree(tree_oid)
-                    .context("Failed to find a git tree in this repository")?;
-                let sig = git2::Signature::now("buildrecall", "bot@buildrecall.com")
-                .context(
-                    "failed to create a git signature (needed to make a commit in the shadow git repo)",
-                )?;
 
-                //  update HEAD so that push works correctly
-                let head = repo.head().ok().map(|h| h.peel_to_commit().ok()).flatten();
-                let parents = head.map(|h| vec![h]).unwrap_or(vec![]);
-                let parents: Vec<&git2::Commit> = parents.iter().collect();
-                repo.commit(
-                    Some("HEAD"),
-                    &sig,
-                    &sig,
-                    "sync with buildrecall",
-                    &tree,
-                    &parents,
-                )
-                .context("Failed to commit to the shadow git project")?;
+                let sync_ref = {
+                    let _lock = RepoLock::acquire(&lock_path, DEFAULT_LOCK_TIMEOUT)?;
+
+                    let tree = repo
+                        .find_tree(synced.tree)
+                        .context("Failed to find a git tree in this repository")?;
+                    let sig = git2::Signature::now("buildrecall", "bot@buildrecall.com")
+                    .context(
+                        "failed to create a git signature (needed to make a commit in the shadow git repo)",
+                    )?;
+
+                    // HEAD only tracks history here, what gets pushed is the per-run ref below.
+                    // Shallow syncs have no parent, the farm only needs the latest tree, and
+                    // push negotiation still skips objects it already has.
+                    let head = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+                    let parents = match (shallow, head) {
+                        (false, Some(h)) => vec![h],
+                        _ => vec![],
+                    };
+                    let parents: Vec<&git2::Commit> = parents.iter().collect();
+                    let commit_oid = repo
+                        .commit(
+                            None,
+                            &sig,
+                            &sig,
+                            "sync with buildrecall",
+                            &tree,
+                            &parents,
+                        )
+                        .context("Failed to commit to the shadow git project")?;
+                    update_head(&repo, commit_oid)
+                        .context("Failed to update HEAD in the shadow git project")?;
+
+                    let sync_ref = sync_ref_name(synced.hash);
+                    repo.reference(&sync_ref, commit_oid, true, "sync with buildrecall")
+                        .context(format!("Failed to create the ref {}", sync_ref))?;
+
+                    sync_ref
+                };
+
+                let accepted = Cell::new(false);
+                let progress = PushProgress::new();
 
                 let mut push_cbs = RemoteCallbacks::new();
                 push_cbs.push_update_reference(|_ref_, msg| {
-                    if let Some(msg) = msg {
-                        eprintln!("git push error {:?}", msg);
+                    match msg {
+                        Some(msg) => eprintln!("git push error {:?}", msg),
+                        None => accepted.set(true),
                     }
                     Ok(())
                 });
+                push_cbs.pack_progress(|stage, current, total| {
+                    progress.pack(stage, current, total)
+                });
+                push_cbs.push_transfer_progress(|current, total, bytes| {
+                    progress.transfer(current, total, bytes)
+                });
 
+                let (query, body) = push_params(&local_config, &args, &synced, retry)?;
+                let body = serde_json::to_vec(&body).context("Failed to serialize the job to push")?;
+                let connection = PushConnection::register(connection_id, body)?;
+                // Only the URL without its query goes into errors
+                let push_url = connection.remote_url("/push")?;
+                let remote_url = format!("{}?{}", push_url, serde_qs::to_string(&query)?);
 
-                let query = serde_qs::to_string(&PushQueryParams{
-                    wait: Some(retry), 
-                    tree_hash: tree_oid.to_string(),
-                    project_slug: slug,
-                    job: args.job, 
-                    container: args.container, 
-                    image,
-                })?;
-
-                let remote_url = format!("{}/push?{}", config.git_host(), query);
                 let mut push_opts = PushOptions::new();
                 push_opts.remote_callbacks(push_cbs);
                 let mut remote = repo
@@ -186,47 +556,95 @@ impl RecallGit {
 
                 //  push to non-main branch so that we dont get "branch is currently checked out" error
                 //  https://stackoverflow.com/questions/2816369/git-push-error-remote-rejected-master-master-branch-is-currently-checked
-                //  TODO: potential race condition as another process could update HEAD before this push
-                let refspecs: &[&str] = &["+HEAD:refs/heads/incoming"];
+                let refspec = format!("+{}:refs/heads/incoming", sync_ref);
                 remote
-                    .push(refspecs, Some(&mut push_opts))
+                    .push(&[refspec.as_str()], Some(&mut push_opts))
                     .context(format!(
                         "Failed to push to the shadow git project with remote: {}",
-                        remote_url
+                        push_url
                     ))?;
+                drop(remote);
+                drop(push_opts);
+
+                let summary = progress.finish(synced.hash.to_string());
+                eprintln!(
+                    "{} ({} sync)",
+                    summary.describe(),
+                    if shallow { "shallow" } else { "full" }
+                );
+
+                if accepted.get() {
+                    let synced = repo
+                        .refname_to_id(&sync_ref)
+                        .context(format!("Failed to find the ref {}", sync_ref))?;
+                    repo.reference(ACKED_REF, synced, true, "accepted by buildrecall")
+                        .context(format!("Failed to update {}", ACKED_REF))?;
+                }
 
-                Ok(())
+                Ok(summary)
             })
             .await
             .context("Failed to spawn the tokio runtime")?
-            .context("Failed to git push")?)
+            .context("Failed to git push")
     }
 }
 
-struct RecallGitTransport;
+impl Drop for RecallGit {
+    fn drop(&mut self) {
+        CONNECTIONS.lock().unwrap().remove(&self.connection_id);
+    }
+}
 
-impl git2::transport::SmartSubtransport for RecallGitTransport {
-    fn action(
-        &self,
-        url: &str,
-        _action: git2::transport::Service,
-    ) -> Result<Box<dyn git2::transport::SmartSubtransportStream>, git2::Error> {
-        git_smart_transport_action(url, _action, Scheme::HTTP)
+/// A connection registered for a single push, so that the transport can send its
+/// body along with the push request. Unregistered when this is dropped.
+struct PushConnection {
+    id: u64,
+}
+
+impl PushConnection {
+    fn register(connection_id: u64, body: Vec<u8>) -> Result<PushConnection> {
+        let context = CONNECTIONS
+            .lock()
+            .unwrap()
+            .get(&connection_id)
+            .map(|c| c.as_ref().clone())
+            .ok_or(anyhow!("This connection has been closed"))?;
+        let id = register_connection(context);
+        PUSH_BODIES.lock().unwrap().insert(id, Arc::new(body));
+        Ok(PushConnection { id })
     }
 
-    fn close(&self) -> Result<(), git2::Error> {
-        Ok(())
+    fn remote_url(&self, path_and_query: &str) -> Result<String> {
+        connection_url(self.id, path_and_query)
+    }
+}
+
+impl Drop for PushConnection {
+    fn drop(&mut self) {
+        CONNECTIONS.lock().unwrap().remove(&self.id);
+        PUSH_BODIES.lock().unwrap().remove(&self.id);
     }
 }
 
-struct RecallsGitTransport;
-impl git2::transport::SmartSubtransport for RecallsGitTransport {
+struct RecallGitTransport {
+    scheme: Scheme,
+    context: Arc<ConnectionContext>,
+    body: Option<Arc<Vec<u8>>>,
+}
+
+impl git2::transport::SmartSubtransport for RecallGitTransport {
     fn action(
         &self,
         url: &str,
         _action: git2::transport::Service,
     ) -> Result<Box<dyn git2::transport::SmartSubtransportStream>, git2::Error> {
-        git_smart_transport_action(url, _action, Scheme::HTTPS)
+        git_smart_transport_action(
+            url,
+            _action,
+            self.scheme.clone(),
+            self.context.clone(),
+            self.body.clone(),
+        )
     }
 
     fn close(&self) -> Result<(), git2::Error> {
@@ -238,63 +656,109 @@ fn git_smart_transport_action(
     url: &str,
     _action: git2::transport::Service,
     scheme: Scheme,
+    context: Arc<ConnectionContext>,
+    body: Option<Arc<Vec<u8>>>,
 ) -> Result<Box<dyn git2::transport::SmartSubtransportStream>, git2::Error> {
-    use git2::{Error, ErrorClass, ErrorCode};
+    use git2::{ErrorClass, ErrorCode};
 
     trace!("creating transport for url {}", url);
 
     let uri = hyper::Uri::try_from(url)
-        .map_err(|e| {
-            error!("{}", &e);
-            e
-        })
-        .map_err(|e| Error::new(ErrorCode::Invalid, ErrorClass::Config, e.to_string()))?;
+        .map_err(|e| transport_error(ErrorCode::Invalid, ErrorClass::Config, e))?;
 
+    // The username is the connection ID, it's not for the server
     let mut parts = uri.into_parts();
     parts.scheme = Some(scheme);
-    let uri = hyper::Uri::from_parts(parts)
-        .map_err(|e| {
-            error!("{}", &e);
-            e
-        })
-        .map_err(|e| Error::new(ErrorCode::Invalid, ErrorClass::Config, e.to_string()))?;
-
-    // let runtime = tokio::runtime::Runtime::new().unwrap();
-    let handle = tokio::runtime::Handle::current();
-    let conn = handle
-        .block_on(git_conn(uri))
-        .map_err(|e| {
-            error!("{}", &e);
-            e
+    parts.authority = parts
+        .authority
+        .map(|a| match a.port() {
+            Some(port) => Authority::try_from(format!("{}:{}", a.host(), port).as_str()),
+            None => Authority::try_from(a.host()),
         })
-        .map_err(|e| Error::new(ErrorCode::GenericError, ErrorClass::Http, e.to_string()))?;
+        .transpose()
+        .map_err(|e| transport_error(ErrorCode::Invalid, ErrorClass::Config, e))?;
+    let uri = hyper::Uri::from_parts(parts)
+        .map_err(|e| transport_error(ErrorCode::Invalid, ErrorClass::Config, e))?;
+
+    // libgit2 wants blocking reads and writes, hyper's upgraded connection is async
+    let timeout = context.timeout;
+    let conn = BlockingStream::open(
+        "brr-git-transport",
+        move || async move { git_conn(uri, &context, body).await },
+        timeout,
+    )
+    .map_err(|e| {
+        transport_error(
+            ErrorCode::GenericError,
+            ErrorClass::Http,
+            format!("{:#}", e),
+        )
+    })?;
 
     Ok(Box::new(conn))
 }
-struct RecallGitConn(hyper::upgrade::Upgraded);
 
-async fn git_conn(url: hyper::Uri) -> Result<RecallGitConn> {
-    // TODO: move this into a param
-    let access_token = read_global_config(get_global_config_dir()?)?
-        .access_token()
+fn transport_error(
+    code: git2::ErrorCode,
+    class: git2::ErrorClass,
+    e: impl std::fmt::Display,
+) -> git2::Error {
+    error!("{}", &e);
+    git2::Error::new(code, class, e.to_string())
+}
+
+async fn git_conn(
+    url: hyper::Uri,
+    context: &ConnectionContext,
+    body: Option<Arc<Vec<u8>>>,
+) -> Result<Upgraded> {
+    let access_token = context
+        .access_token
+        .clone()
         .ok_or(anyhow::anyhow!("no configured access_token"))?;
     //  https://github.com/hyperium/hyper/blob/master/examples/upgrades.rs
-    let upgrade_req = hyper::Request::builder()
+    let host = url
+        .authority()
+        .map(|a| a.to_string())
+        .ok_or(anyhow!("{} doesn't have a host", url))?;
+    let mut upgrade_req = hyper::Request::builder();
+    if body.is_some() {
+        upgrade_req = upgrade_req.header(CONTENT_TYPE, "application/json");
+    }
+    let upgrade_req = upgrade_req
         .method("POST")
-        .uri(url.to_string())
+        .uri(
+            url.path_and_query()
+                .map(|p| p.as_str())
+                .unwrap_or("/")
+                .to_string(),
+        )
+        .header(HOST, host)
         .header(UPGRADE, "recall-git")
         .header(AUTHORIZATION, format!("Bearer {}", access_token))
-        .body(Body::empty())
-        .context(format!("Failed to construct post for {}", url.to_string()))?;
-
-    use hyper_tls::HttpsConnector;
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
+        .body(
+            body.map(|b| Body::from(b.to_vec()))
+                .unwrap_or_else(Body::empty),
+        )
+        .context(format!("Failed to construct post for {}", url))?;
+
+    // A connection of our own rather than a hyper Client, so that it goes through
+    // the proxy and TLS settings
+    let target = url::Url::parse(&url.to_string())?;
+    let stream = context.network.connect_tls(&target).await?;
+    let (mut sender, conn) = hyper::client::conn::handshake(stream)
+        .await
+        .context(format!("Failed to connect to {}", url))?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            error!("git connection failed: {}", e);
+        }
+    });
 
-    let res = client.request(upgrade_req).await.context(format!(
-        "Failed to send upgrade request for {}",
-        url.to_string()
-    ))?;
+    let res = sender
+        .send_request(upgrade_req)
+        .await
+        .context(format!("Failed to send upgrade request for {}", url))?;
 
     if res.status().eq(&StatusCode::UNAUTHORIZED) {
         return Err(anyhow!("Something is wrong with your access token, perhaps you've been logged out by the server? You can login again at https://buildrecall.com/setup".to_string()));
@@ -302,29 +766,9 @@ async fn git_conn(url: hyper::Uri) -> Result<RecallGitConn> {
 
     let conn = hyper::upgrade::on(res)
         .await
-        .context(format!("Failed to upgrade: {}", url.to_string()))?;
-
-    Ok(RecallGitConn(conn))
-}
+        .context(format!("Failed to upgrade: {}", url))?;
 
-impl std::io::Read for RecallGitConn {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let handle = tokio::runtime::Handle::current();
-        let result = handle.block_on(async { self.0.read(buf).await });
-        result
-    }
-}
-
-impl std::io::Write for RecallGitConn {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let handle = tokio::runtime::Handle::current();
-        handle.block_on(self.0.write(buf))
-    }
-
-    fn flush(&mut self) -> std::io::Result<()> {
-        let handle = tokio::runtime::Handle::current();
-        handle.block_on(self.0.flush())
-    }
+    Ok(conn)
 }
 
 #[cfg(test)]
@@ -335,19 +779,29 @@ mod test {
     use hyper::{header, upgrade::OnUpgrade, StatusCode};
 
     use super::*;
+    use crate::api::{PushBody, PushQueryParams};
+
     #[tokio::test]
     async fn test_git_upgrade() -> Result<()> {
-        tracing_subscriber::fmt::init();
-
-        tokio::spawn(TestGitRemote::start());
-        trace!("initing git transport");
-        init_git_transport();
-        trace!("init git transport");
-
-        let handle = tokio::runtime::Handle::current();
+        let _ = tracing_subscriber::fmt::try_init();
 
-        handle
-            .spawn_blocking(move || -> Result<_> {
+        let addr = TestGitRemote::start()?;
+        let config_dir = tempdir::TempDir::new(".buildrecall")?;
+        let g = RecallGit::with_connection(
+            config_dir.path().to_path_buf(),
+            ConnectionContext {
+                git_host: format!("recall+git://{}", addr),
+                access_token: Some("test-token".to_string()),
+                timeout: Duration::from_secs(30),
+                network: NetworkConfig::default(),
+            },
+        );
+        let remote_url = g.remote_url("/push")?;
+
+        // A plain thread, without a tokio runtime to lean on
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        std::thread::spawn(move || {
+            let push = || -> Result<()> {
                 //  push to non-main branch so that we dont get "branch is currently checked out" error
                 //  https://stackoverflow.com/questions/2816369/git-push-error-remote-rejected-master-master-branch-is-currently-checked
                 let refspecs: &[&str] = &["+HEAD:refs/heads/incoming"];
@@ -363,16 +817,193 @@ mod test {
 
                 let repo = TempGitRepo::init()?;
                 trace!("temp git repo ready");
-                let mut remote = repo.remote_anonymous("recall+git://localhost:7890/push")?;
-                // let mut remote = repo.remote("recall", "recall+git://localhost:7890")?;
+                let mut remote = repo.remote_anonymous(&remote_url)?;
                 Ok(remote.push(refspecs, Some(&mut push_opts))?)
-            })
-            .await??;
-        trace!("here");
+            };
+            let _ = done_tx.send(push());
+        });
+        done_rx.await??;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pushes_the_resolved_job() -> Result<()> {
+        let (addr, requests) = TestGitRemote::start_recording()?;
+        let config_dir = tempdir::TempDir::new(".buildrecall")?;
+        let worktree = tempdir::TempDir::new(".project")?;
+        std::fs::write(
+            worktree.path().join("buildrecall.toml"),
+            r#"[project]
+name = "app"
+
+[vars]
+profile = "release"
+
+[templates.rust]
+run_prefix = "rustup show"
+artifacts = ["target/release"]
+
+[jobs.build]
+extends = "rust"
+run = "cargo build --${profile} # ${job.name} in ${container.name}, not ${HOME}"
+timeout = "45m"
+
+[containers.linux]
+image = "rust:1.55"
+"#,
+        )?;
+
+        let g = RecallGit::with_connection(
+            config_dir.path().to_path_buf(),
+            ConnectionContext {
+                git_host: format!("recall+git://{}", addr),
+                access_token: Some("test-token".to_string()),
+                timeout: Duration::from_secs(30),
+                network: NetworkConfig::default(),
+            },
+        );
+        let args = JobArgs {
+            job: "build".to_string(),
+            container: "linux".to_string(),
+            force: false,
+            timeout: None,
+        };
+        g.push_worktree(
+            "app".to_string(),
+            worktree.path().to_path_buf(),
+            false,
+            args,
+        )
+        .await?;
+
+        let (uri, body) = requests.lock().unwrap()[0].clone();
+        let pushed: PushQueryParams = serde_qs::from_str(uri.query().unwrap())?;
+        assert_eq!(pushed.image, "rust:1.55");
+        assert_eq!(pushed.timeout_secs, Some(45 * 60));
+        // The script isn't in the URL, it's in the body
+        assert!(!uri.to_string().contains("cargo"));
+        let body: PushBody = serde_json::from_slice(&body)?;
+        assert_eq!(body.submodules, None);
+        let job = body.job_config.unwrap();
+        assert_eq!(
+            job.run,
+            "rustup show\ncargo build --release # build in linux, not ${HOME}\n"
+        );
+        assert_eq!(job.artifacts, vec!["target/release"]);
+        assert_eq!(job.extends, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pushes_after_gc_following_a_hash_only_sync() -> Result<()> {
+        let addr = TestGitRemote::start()?;
+        let config_dir = tempdir::TempDir::new(".buildrecall")?;
+        let worktree = tempdir::TempDir::new(".project")?;
+        std::fs::write(
+            worktree.path().join("buildrecall.toml"),
+            "[project]\nname = \"app\"\n\n[jobs.build]\nrun = \"make\"\n\n[containers.linux]\nimage = \"rust:1.55\"\n",
+        )?;
+        std::fs::write(worktree.path().join("main.rs"), "fn main() {}")?;
+
+        let g = RecallGit::with_connection(
+            config_dir.path().to_path_buf(),
+            ConnectionContext {
+                git_host: format!("recall+git://{}", addr),
+                access_token: Some("test-token".to_string()),
+                timeout: Duration::from_secs(30),
+                network: NetworkConfig::default(),
+            },
+        );
+        let args = JobArgs {
+            job: "build".to_string(),
+            container: "linux".to_string(),
+            force: false,
+            timeout: None,
+        };
+        let slug = "app".to_string();
+        let push = || {
+            g.push_worktree(
+                slug.clone(),
+                worktree.path().to_path_buf(),
+                false,
+                args.clone(),
+            )
+        };
+        push().await?;
+
+        // Written a while before the sync, so that the index trusts its stat cache
+        std::fs::write(worktree.path().join("main.rs"), "fn main() { changed() }")?;
+        std::thread::sleep(Duration::from_millis(1100));
+
+        // What `brr run --dry-run` or the pull before a push does
+        let repo = g.open_repo(slug.clone(), worktree.path()).await?;
+        write_worktree_tree(&repo, &SyncConfig::default())?;
+
+        let path = repo_path(config_dir.path().to_path_buf(), slug.clone())?;
+        crate::gc::repack(&Repository::open_bare(&path)?, Duration::from_secs(0))?;
+
+        push().await?;
 
         Ok(())
     }
 
+    #[test]
+    fn test_unstages_deleted_and_ignored_files() {
+        let tmp = tempdir::TempDir::new(".unstage").unwrap();
+        let worktree = tmp.path().join("project");
+        std::fs::create_dir_all(&worktree).unwrap();
+        for name in &["keep.rs", "gone.rs", "big.bin"] {
+            std::fs::write(worktree.join(name), name).unwrap();
+        }
+
+        let repo = git2::Repository::init_bare(tmp.path().join("shadow")).unwrap();
+        repo.set_workdir(&worktree, false).unwrap();
+        write_worktree_tree(&repo, &SyncConfig::default()).unwrap();
+
+        std::fs::remove_file(worktree.join("gone.rs")).unwrap();
+        guard::add_to_brrignore(&worktree, &["big.bin"]).unwrap();
+        guard::load_brrignore(&repo, &worktree).unwrap();
+        let tree_oid = write_worktree_tree(&repo, &SyncConfig::default())
+            .unwrap()
+            .tree;
+
+        let tree = repo.find_tree(tree_oid).unwrap();
+        let mut names: Vec<_> = tree.iter().map(|e| e.name().unwrap().to_string()).collect();
+        names.sort();
+        assert_eq!(names, vec![".brrignore", "keep.rs"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stages_executable_bits_and_symlinks() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let tmp = tempdir::TempDir::new(".modes").unwrap();
+        let worktree = tmp.path().join("project");
+        std::fs::create_dir_all(&worktree).unwrap();
+        let script = worktree.join("build.sh");
+        std::fs::write(&script, "#!/bin/sh\ncargo build").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(worktree.join("README.md"), "# hello").unwrap();
+        symlink("README.md", worktree.join("README")).unwrap();
+
+        let repo = git2::Repository::init_bare(tmp.path().join("shadow")).unwrap();
+        track_modes_and_symlinks(&repo).unwrap();
+        repo.set_workdir(&worktree, false).unwrap();
+        let tree_oid = write_worktree_tree(&repo, &SyncConfig::default())
+            .unwrap()
+            .tree;
+        let tree = repo.find_tree(tree_oid).unwrap();
+
+        assert_eq!(tree.get_name("build.sh").unwrap().filemode(), 0o100755);
+        assert_eq!(tree.get_name("README.md").unwrap().filemode(), 0o100644);
+        let link = tree.get_name("README").unwrap();
+        assert_eq!(link.filemode(), 0o120000);
+        assert_eq!(repo.find_blob(link.id()).unwrap().content(), b"README.md");
+    }
+
     struct TempGitRepo {
         path: std::path::PathBuf,
         repo: git2::Repository,
@@ -422,15 +1053,39 @@ mod test {
         }
     }
 
+    // The URI and body of each push
+    type Requests = Arc<Mutex<Vec<(axum::http::Uri, hyper::body::Bytes)>>>;
+
     struct TestGitRemote {}
     impl TestGitRemote {
-        async fn start() {
-            let app = axum::Router::new().route("/", axum::handler::post(handle_test_git_conn));
+        /// Serves on a free port on localhost, and returns its address
+        fn start() -> Result<std::net::SocketAddr> {
+            Ok(Self::start_recording()?.0)
+        }
 
-            axum::Server::bind(&"127.0.0.1:7890".parse().unwrap())
-                .serve(app.into_make_service())
-                .await
-                .unwrap();
+        /// Same as `start`, and keeps each push it receives
+        fn start_recording() -> Result<(std::net::SocketAddr, Requests)> {
+            let requests = Requests::default();
+            let recorded = requests.clone();
+            let app = axum::Router::new().route(
+                "/push",
+                axum::handler::post(move |mut req: axum::http::Request<axum::body::Body>| {
+                    let recorded = recorded.clone();
+                    async move {
+                        let body = std::mem::take(req.body_mut());
+                        let body = hyper::body::to_bytes(body).await.unwrap();
+                        recorded.lock().unwrap().push((req.uri().clone(), body));
+                        handle_test_git_conn(req).await
+                    }
+                }),
+            );
+
+            let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+            let addr = listener.local_addr()?;
+            let server = axum::Server::from_tcp(listener)?.serve(app.into_make_service());
+            tokio::spawn(async move { server.await.unwrap() });
+
+            Ok((addr, requests))
         }
     }
 
@@ -444,7 +1099,7 @@ mod test {
             trace!("upgrade done!");
             let (mut rd, mut wr) = tokio::io::split(conn);
 
-            git2::Repository::init("/tmp/gittest");
+            let _ = git2::Repository::init("/tmp/gittest");
             let mut child = tokio::process::Command::new("git")
                 .args(["receive-pack", "/tmp/gittest"])
                 .stdin(std::process::Stdio::piped())
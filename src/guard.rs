@@ -0,0 +1,316 @@
+use anyhow::{anyhow, Context, Result};
+use dialoguer::Confirm;
+use git2::{ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use crate::{
+    config_local::{OversizeAction, SyncConfig},
+    git::ACKED_REF,
+    units::format_bytes,
+};
+
+/// Like a .gitignore, but only for what brr sends to the farm
+pub const BRRIGNORE: &str = ".brrignore";
+
+// How many files to list when a push is too big
+const MAX_LISTED: usize = 10;
+
+pub struct NewFile {
+    pub path: String,
+    pub size: u64,
+}
+
+/// The files in a tree that the farm doesn't have yet, checked against the
+/// `[sync]` limits in buildrecall.toml
+pub struct SizeReport {
+    /// Set when the farm has nothing yet, so the whole tree is new
+    pub initial: bool,
+    pub max_file_size: u64,
+    pub max_push_size: u64,
+    pub new_bytes: u64,
+    /// Biggest first
+    pub new_files: Vec<NewFile>,
+}
+
+impl SizeReport {
+    pub fn is_over_limit(&self) -> bool {
+        self.new_bytes > self.max_push_size
+            || self.new_files.iter().any(|f| f.size > self.max_file_size)
+    }
+
+    /// The files that are over the per-file limit, or if none are, the files
+    /// that contribute the most to the total
+    pub fn offenders(&self) -> Vec<&NewFile> {
+        let too_big = self
+            .new_files
+            .iter()
+            .filter(|f| f.size > self.max_file_size)
+            .collect::<Vec<_>>();
+
+        if too_big.is_empty() {
+            self.new_files.iter().take(MAX_LISTED).collect()
+        } else {
+            too_big
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let mut out = format!(
+            "{} {} of new files (the limit is {} per push and {} per file).\nThe largest are:\n",
+            if self.initial {
+                "This is the initial upload of the project, it would upload"
+            } else {
+                "This push would upload"
+            },
+            format_bytes(self.new_bytes),
+            format_bytes(self.max_push_size),
+            format_bytes(self.max_file_size)
+        );
+        for f in self.offenders().iter().take(MAX_LISTED) {
+            out.push_str(&format!("  {:>10}  {}\n", format_bytes(f.size), f.path));
+        }
+        out
+    }
+}
+
+/// Checks the new files in `tree_oid` against the `[sync]` limits. Returns whether
+/// any files were added to .brrignore, in which case the tree should be staged again.
+/// Only offers to do that when `offer_to_ignore` is set and someone is at the terminal.
+pub fn check_push_size(
+    repo: &Repository,
+    tree_oid: Oid,
+    sync: &SyncConfig,
+    worktree: &Path,
+    offer_to_ignore: bool,
+) -> Result<bool> {
+    let report = scan_new_files(repo, tree_oid, sync)?;
+    if !report.is_over_limit() {
+        return Ok(false);
+    }
+
+    if sync.on_oversize() == OversizeAction::Warn {
+        eprintln!("Warning: {}", report.describe());
+        return Ok(false);
+    }
+
+    let interactive = atty::is(atty::Stream::Stdin) && atty::is(atty::Stream::Stderr);
+    if offer_to_ignore && interactive {
+        eprint!("{}", report.describe());
+        let paths = report
+            .offenders()
+            .iter()
+            .map(|f| f.path.as_str())
+            .collect::<Vec<_>>();
+
+        if Confirm::new()
+            .with_prompt(format!(
+                "Add these {} files to {} and continue?",
+                paths.len(),
+                BRRIGNORE
+            ))
+            .default(false)
+            .interact()?
+        {
+            add_to_brrignore(worktree, &paths)?;
+            load_brrignore(repo, worktree)?;
+            return Ok(true);
+        }
+    }
+
+    Err(anyhow!(
+        "{}\nAdd them to {}, raise max_file_size or max_push_size in the [sync] section of buildrecall.toml, or rerun with --force.",
+        report.describe().trim_end(),
+        BRRIGNORE
+    ))
+}
+
+/// Lists the blobs in `tree_oid` that weren't in the last tree the farm accepted
+pub fn scan_new_files(repo: &Repository, tree_oid: Oid, sync: &SyncConfig) -> Result<SizeReport> {
+    let mut known: HashSet<Oid> = HashSet::new();
+    let acked = repo.find_reference(ACKED_REF).ok();
+    let initial = acked.is_none();
+    if let Some(acked) = acked {
+        let acked_tree = acked.peel_to_tree()?;
+        acked_tree.walk(TreeWalkMode::PreOrder, |_, entry| {
+            known.insert(entry.id());
+            TreeWalkResult::Ok
+        })?;
+    }
+
+    let odb = repo.odb()?;
+    let tree = repo.find_tree(tree_oid)?;
+    let mut seen: HashSet<Oid> = HashSet::new();
+    let mut new_files = vec![];
+    let mut failed = None;
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if entry.kind() != Some(ObjectType::Blob) || known.contains(&entry.id()) {
+            return TreeWalkResult::Ok;
+        }
+        if !seen.insert(entry.id()) {
+            // Same content as a file we've already counted
+            return TreeWalkResult::Ok;
+        }
+
+        match odb.read_header(entry.id()) {
+            Ok((size, _)) => {
+                new_files.push(NewFile {
+                    path: format!("{}{}", root, entry.name().unwrap_or_default()),
+                    size: size as u64,
+                });
+                TreeWalkResult::Ok
+            }
+            Err(e) => {
+                failed = Some(e);
+                TreeWalkResult::Abort
+            }
+        }
+    })?;
+    if let Some(e) = failed {
+        return Err(e).context("Failed to read the size of a file in the shadow git repo");
+    }
+
+    new_files.sort_by_key(|f| Reverse(f.size));
+
+    Ok(SizeReport {
+        initial,
+        max_file_size: sync.max_file_size().0,
+        max_push_size: sync.max_push_size().0,
+        new_bytes: new_files.iter().map(|f| f.size).sum(),
+        new_files,
+    })
+}
+
+/// Adds the worktree's .brrignore (if there is one) to the shadow repo's ignore rules
+pub fn load_brrignore(repo: &Repository, worktree: &Path) -> Result<()> {
+    let path = worktree.join(BRRIGNORE);
+    if !path.is_file() {
+        return Ok(());
+    }
+
+    let rules = fs::read_to_string(&path).context(format!("Can't read path {:?}", path))?;
+    repo.add_ignore_rule(&rules)
+        .context(format!("Failed to use the ignore rules in {:?}", path))
+}
+
+/// Appends exact (root-anchored) paths to the worktree's .brrignore
+pub fn add_to_brrignore(worktree: &Path, paths: &[&str]) -> Result<()> {
+    let path = worktree.join(BRRIGNORE);
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .context(format!("Failed to open {:?}", path))?;
+
+    let mut lines = String::new();
+    if !existing.is_empty() && !existing.ends_with('\n') {
+        lines.push('\n');
+    }
+    for p in paths {
+        if p.contains('\n') {
+            return Err(anyhow!("Can't add {:?} to {}", p, BRRIGNORE));
+        }
+        lines.push_str(&format!("/{}\n", p));
+    }
+
+    file.write_all(lines.as_bytes())
+        .context(format!("Failed to write to {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use git2::Repository;
+    use std::fs;
+    use tempdir::TempDir;
+
+    use super::{add_to_brrignore, scan_new_files};
+    use crate::{config_local::SyncConfig, git::ACKED_REF, units::ByteSize};
+
+    #[test]
+    fn test_only_counts_files_the_farm_does_not_have() {
+        let tmp = TempDir::new(".guard")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let repo = Repository::init_bare(tmp.path().join("repo")).unwrap();
+        let sig = git2::Signature::now("test", "test").unwrap();
+
+        let old = repo.blob(&[0; 300]).unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("old", old, 0o100644).unwrap();
+        let acked_tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        let acked = repo
+            .commit(None, &sig, &sig, "acked", &acked_tree, &[])
+            .unwrap();
+        repo.reference(ACKED_REF, acked, true, "test").unwrap();
+
+        let new = repo.blob(&[1; 200]).unwrap();
+        builder.insert("new", new, 0o100644).unwrap();
+        let tree = builder.write().unwrap();
+
+        let sync = SyncConfig {
+            max_file_size: Some(ByteSize(100)),
+            ..SyncConfig::default()
+        };
+        let report = scan_new_files(&repo, tree, &sync).unwrap();
+
+        assert_eq!(report.new_bytes, 200);
+        assert!(report.is_over_limit());
+        let offenders = report.offenders();
+        assert_eq!(offenders.len(), 1);
+        assert_eq!(offenders[0].path, "new");
+    }
+
+    #[test]
+    fn test_limits_the_initial_upload() {
+        let tmp = TempDir::new(".guard")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let repo = Repository::init_bare(tmp.path().join("repo")).unwrap();
+
+        let mut builder = repo.treebuilder(None).unwrap();
+        for name in &["a", "b", "c"] {
+            let blob = repo.blob(name.repeat(100).as_bytes()).unwrap();
+            builder.insert(name, blob, 0o100644).unwrap();
+        }
+        let tree = builder.write().unwrap();
+
+        // A stray build folder is as likely on the first sync as on any other
+        let mut sync = SyncConfig {
+            max_push_size: Some(ByteSize(200)),
+            ..SyncConfig::default()
+        };
+        let report = scan_new_files(&repo, tree, &sync).unwrap();
+        assert!(report.initial);
+        assert_eq!(report.new_bytes, 300);
+        assert!(report.is_over_limit());
+        assert!(report.describe().starts_with("This is the initial upload"));
+
+        // Raising the limit is the way past it
+        sync.max_push_size = Some(ByteSize(300));
+        let report = scan_new_files(&repo, tree, &sync).unwrap();
+        assert!(!report.is_over_limit());
+    }
+
+    #[test]
+    fn test_appends_anchored_paths_to_brrignore() {
+        let tmp = TempDir::new(".guard")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        fs::write(tmp.path().join(".brrignore"), "*.log").unwrap();
+
+        add_to_brrignore(tmp.path(), &["data/big.bin"]).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(tmp.path().join(".brrignore")).unwrap(),
+            "*.log\n/data/big.bin\n"
+        );
+    }
+}
@@ -1,36 +1,75 @@
 use anyhow::{anyhow, Context, Result};
 use crypto::digest::Digest;
 use crypto::sha3::Sha3;
-use ignore::gitignore::Gitignore;
+use ignore::gitignore::GitignoreBuilder;
+use std::ffi::OsStr;
 use std::fs;
 use std::path::PathBuf;
 use walkdir::WalkDir;
 
-pub fn list_non_ignored_files_in_dir(dir: &PathBuf) -> Result<Vec<PathBuf>> {
-    let (gi, err) = Gitignore::new(dir.clone().join(".gitignore"));
+use crate::{
+    config_local::{LfsMode, SubmoduleMode, SyncConfig},
+    guard::BRRIGNORE,
+    lfs, submodules,
+};
+
+/// Lists what a sync of `dir` would send. Submodules and nested repos follow the
+/// `[sync.submodules]` settings: their own folder is listed, so that `hash_files`
+/// can hash the commit they're at, and their files are too unless they're only
+/// sent as a gitlink.
+pub fn list_non_ignored_files_in_dir(dir: &PathBuf, sync: &SyncConfig) -> Result<Vec<PathBuf>> {
+    let mut builder = GitignoreBuilder::new(dir);
+    for name in &[".gitignore", BRRIGNORE] {
+        let path = dir.join(name);
+        if !path.is_file() {
+            continue;
+        }
 
-    // We can't tell wether or not there's a git ignore
-    if err.is_some() {
-        return Err(err.unwrap().into());
+        // We can't tell wether or not this file is ignored
+        if let Some(err) = builder.add(path) {
+            return Err(err.into());
+        }
     }
+    let gi = builder.build()?;
 
-    let paths = WalkDir::new(dir);
+    let mut paths = WalkDir::new(dir).into_iter();
 
     let mut matches = vec![];
-    for f in paths {
+    while let Some(f) = paths.next() {
         let fres = f?;
         let fpath = fres.path();
-        let fmeta = fres.metadata().context(format!(
-            "Can't read the metadata of the file {:?}",
-            fpath.clone()
-        ))?;
+        let fmeta = fres
+            .metadata()
+            .context(format!("Can't read the metadata of the file {:?}", fpath))?;
 
         let stripped = fpath.strip_prefix(dir)?;
+        // Nested repos and submodules have their own .git
+        if stripped.file_name() == Some(OsStr::new(".git")) {
+            if fmeta.is_dir() {
+                paths.skip_current_dir();
+            }
+            continue;
+        }
+
+        if fmeta.is_dir() && submodules::is_nested_repo(dir, stripped) {
+            let path = stripped
+                .to_str()
+                .ok_or(anyhow!("Failed to convert {:?} to string", stripped))?
+                .replace('\\', "/");
+            match sync.submodule_mode(&path) {
+                SubmoduleMode::Materialize => {}
+                SubmoduleMode::Gitlink => paths.skip_current_dir(),
+                SubmoduleMode::Skip => {
+                    paths.skip_current_dir();
+                    continue;
+                }
+            }
+        }
+
         let is_ignored = gi
             .matched_path_or_any_parents(stripped, fmeta.is_dir())
             .is_ignore();
-        let is_git = stripped.starts_with(".git");
-        if !is_ignored && !is_git {
+        if !is_ignored {
             matches.push(fpath.to_path_buf());
         }
     }
@@ -39,62 +78,112 @@ pub fn list_non_ignored_files_in_dir(dir: &PathBuf) -> Result<Vec<PathBuf>> {
 }
 
 /// Computes a sha-3 hash of the files in sorted order
-/// hash = sha3(bytes(file_path_relative_to_root) + bytes(file_contents))
-pub async fn hash_files(root: &PathBuf, paths: Vec<PathBuf>) -> Result<String> {
+/// hash = sha3(bytes(file_path_relative_to_root) + bytes(file_mode) + bytes(file_contents))
+/// Modes are git's: "100644", "100755" for executables and "120000" for symlinks,
+/// whose contents are their target. Symlinks are never followed. The folder of a
+/// submodule or nested repo is hashed as "160000", with the ID of the commit it
+/// has checked out as its contents, like the shadow git repo keys the tree on.
+/// Git LFS pointers follow `[sync] lfs` like a sync does.
+pub async fn hash_files(root: &PathBuf, paths: Vec<PathBuf>, sync: &SyncConfig) -> Result<String> {
     let mut sorted = paths.clone();
     sorted.sort_by(|a, b| b.cmp(a));
 
     // Start hash
     let mut hasher = Sha3::sha3_256();
+    let mut pointers = vec![];
 
     for path in sorted {
-        if path.is_dir() {
-            continue;
-        }
-
-        /*
-        Potentially could be a bug here: Executables on windows vs unix.
-        - On Windows, every file is executable
-        - On Unix, only files where metadata.mode() & 0o111 != 0
-
-        If I make a file executable in git on unix, it will store that, so when I git pull, any file someone else marked as exec is now exec.
-
-        This might lead to problems where, someone creates a file on a windows computer, that's supposed to be executable, but the unix build farm server doesn't know it's supposed to be exec
-        */
+        let meta = fs::symlink_metadata(&path)
+            .context(format!("Can't read the metadata of the file {:?}", path))?;
 
         let cloned_path = path.clone();
         let result = cloned_path
             .strip_prefix(root)
             .context(format!("{:?} is not a child of {:?}", path, root))?;
+        if meta.is_dir() && !submodules::is_nested_repo(root, result) {
+            continue;
+        }
         let as_str = result
             .to_str()
             .ok_or(anyhow!("Failed to convert {:?} to string", result))?;
 
         let mut filepath = as_str.as_bytes().to_vec();
-        let mut contents = fs::read(path.clone())
-            .context(format!("Failed to read this file: {:?}", path.clone()))?;
+        let (mode, mut contents) = if meta.is_dir() {
+            let commit = submodules::checked_out_commit(&path);
+            (MODE_GITLINK, commit.to_string().into_bytes())
+        } else if meta.file_type().is_symlink() {
+            let target =
+                fs::read_link(&path).context(format!("Failed to read the symlink {:?}", path))?;
+            let target = target
+                .to_str()
+                .ok_or(anyhow!("Failed to convert {:?} to string", target))?;
+            (MODE_SYMLINK, target.as_bytes().to_vec())
+        } else {
+            let mut contents = fs::read(path.clone())
+                .context(format!("Failed to read this file: {:?}", path.clone()))?;
+            // Hash what the pointer stands for, like the shadow git repo does
+            if let Some(pointer) = lfs::parse_pointer(&contents) {
+                if sync.lfs() == LfsMode::Fail {
+                    pointers.push(as_str.replace('\\', "/"));
+                    continue;
+                }
+                contents = lfs::read_object(&path, &pointer)?;
+            }
+            (file_mode(&meta), contents)
+        };
 
         let mut input = vec![];
         input.append(&mut filepath);
+        input.extend_from_slice(mode.as_bytes());
         input.append(&mut contents);
 
         hasher.input(&input);
     }
 
+    if !pointers.is_empty() {
+        pointers.sort();
+        return Err(lfs::pointers_not_allowed(&pointers));
+    }
+
     Ok(hasher.result_str())
 }
 
+const MODE_FILE: &str = "100644";
+const MODE_EXECUTABLE: &str = "100755";
+const MODE_SYMLINK: &str = "120000";
+const MODE_GITLINK: &str = "160000";
+
+#[cfg(unix)]
+fn file_mode(meta: &fs::Metadata) -> &'static str {
+    use std::os::unix::fs::PermissionsExt;
+
+    if meta.permissions().mode() & 0o111 != 0 {
+        MODE_EXECUTABLE
+    } else {
+        MODE_FILE
+    }
+}
+
+// Windows has no executable bit, so there every file hashes as non-executable,
+// same as git does with core.filemode off
+#[cfg(not(unix))]
+fn file_mode(_meta: &fs::Metadata) -> &'static str {
+    MODE_FILE
+}
+
 #[cfg(test)]
 mod tests {
     use anyhow::Context;
     use itertools::Itertools;
     use std::fs::{create_dir_all, File};
 
+    use std::collections::HashMap;
     use std::io::Write;
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
     use std::vec;
     use tempdir::TempDir;
 
+    use crate::config_local::{LfsMode, SubmoduleMode, SyncConfig};
     use crate::hash::hash_files;
 
     use super::list_non_ignored_files_in_dir;
@@ -116,16 +205,22 @@ mod tests {
 
         // Compute the original hash
         let root_1 = &tmp_1.path().to_path_buf();
-        let files_1 = list_non_ignored_files_in_dir(&root_1.clone()).unwrap();
-        let hash_1 = hash_files(&root_1.clone(), files_1).await.unwrap();
+        let files_1 =
+            list_non_ignored_files_in_dir(&root_1.clone(), &SyncConfig::default()).unwrap();
+        let hash_1 = hash_files(&root_1.clone(), files_1, &SyncConfig::default())
+            .await
+            .unwrap();
 
         // Change the code in a subdirectory
         bfile.write_all(b"a totally different thing").unwrap();
 
         // Compute the new hash
         let root_2 = &tmp_1.path().to_path_buf();
-        let files_2 = list_non_ignored_files_in_dir(&root_2.clone()).unwrap();
-        let hash_2 = hash_files(&root_2.clone(), files_2).await.unwrap();
+        let files_2 =
+            list_non_ignored_files_in_dir(&root_2.clone(), &SyncConfig::default()).unwrap();
+        let hash_2 = hash_files(&root_2.clone(), files_2, &SyncConfig::default())
+            .await
+            .unwrap();
 
         // Show they're different
         assert_ne!(hash_1, hash_2);
@@ -160,11 +255,17 @@ mod tests {
 
         // Hash both files, outputs should be the asme
         let root_1 = &tmp_1.path().to_path_buf();
-        let files_1 = list_non_ignored_files_in_dir(&root_1.clone()).unwrap();
-        let hash_1 = hash_files(&root_1.clone(), files_1).await.unwrap();
+        let files_1 =
+            list_non_ignored_files_in_dir(&root_1.clone(), &SyncConfig::default()).unwrap();
+        let hash_1 = hash_files(&root_1.clone(), files_1, &SyncConfig::default())
+            .await
+            .unwrap();
         let root_2 = tmp_2.path().to_path_buf();
-        let files_2 = list_non_ignored_files_in_dir(&root_2.clone()).unwrap();
-        let hash_2 = hash_files(&root_2.clone(), files_2).await.unwrap();
+        let files_2 =
+            list_non_ignored_files_in_dir(&root_2.clone(), &SyncConfig::default()).unwrap();
+        let hash_2 = hash_files(&root_2.clone(), files_2, &SyncConfig::default())
+            .await
+            .unwrap();
 
         assert_eq!(hash_1, hash_2);
     }
@@ -200,7 +301,7 @@ mod tests {
         let mut ffile = File::create(f.clone()).unwrap();
         ffile.write_all(b"Some text here; () => {}").unwrap();
 
-        let files = vec![a, b, c, d, e, f];
+        let files = [a, b, c, d, e, f];
 
         let paths = files
             .iter()
@@ -212,7 +313,8 @@ mod tests {
         for p in paths {
             let hash = hash_files(
                 &Path::new(tmp.as_ref()).to_path_buf(),
-                p.clone().iter().cloned().map(|e| e.clone()).collect_vec(),
+                p.clone().iter().cloned().cloned().collect_vec(),
+                &SyncConfig::default(),
             )
             .await
             .unwrap();
@@ -221,4 +323,197 @@ mod tests {
 
         assert!(hashes.iter().all_equal());
     }
+
+    // checks that the executable bit is part of the hash
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_executable_bit_changes_the_hash() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TempDir::new(".hash")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let script = tmp.path().join("build.sh");
+        let mut file = File::create(script.clone()).unwrap();
+        file.write_all(b"#!/bin/sh\ncargo build").unwrap();
+
+        let root = tmp.path().to_path_buf();
+        let before = hash_files(&root, vec![script.clone()], &SyncConfig::default())
+            .await
+            .unwrap();
+
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let after = hash_files(&root, vec![script], &SyncConfig::default())
+            .await
+            .unwrap();
+
+        assert_ne!(before, after);
+    }
+
+    // checks that symlinks are hashed as links, not as what they point to
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlinks_are_not_followed() {
+        let tmp = TempDir::new(".hash")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let outside = TempDir::new(".hash_outside")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let target = outside.path().join("config.json");
+        std::fs::write(&target, "{}").unwrap();
+        std::os::unix::fs::symlink(&target, tmp.path().join("config.json")).unwrap();
+
+        let root = tmp.path().to_path_buf();
+        let files = list_non_ignored_files_in_dir(&root, &SyncConfig::default()).unwrap();
+        let before = hash_files(&root, files.clone(), &SyncConfig::default())
+            .await
+            .unwrap();
+
+        // Changing what the link points to doesn't change the tree
+        std::fs::write(&target, "{\"changed\": true}").unwrap();
+        assert_eq!(
+            hash_files(&root, files.clone(), &SyncConfig::default())
+                .await
+                .unwrap(),
+            before
+        );
+
+        // But changing where it points does
+        std::fs::remove_file(tmp.path().join("config.json")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), tmp.path().join("config.json")).unwrap();
+        assert_ne!(
+            hash_files(&root, files, &SyncConfig::default())
+                .await
+                .unwrap(),
+            before
+        );
+    }
+
+    fn commit_all(repo: &git2::Repository) {
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test").unwrap();
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let parents = parent.iter().collect::<Vec<_>>();
+        repo.commit(Some("HEAD"), &sig, &sig, "test", &tree, &parents)
+            .unwrap();
+    }
+
+    async fn hash_dir(root: &PathBuf, sync: &SyncConfig) -> String {
+        let files = list_non_ignored_files_in_dir(root, sync).unwrap();
+        hash_files(root, files, sync).await.unwrap()
+    }
+
+    // checks that nested repos are hashed the way they're synced
+    #[tokio::test]
+    async fn test_follows_the_submodule_settings() {
+        let tmp = TempDir::new(".hash")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let root = tmp.path().to_path_buf();
+        let dep = root.join("dep");
+        create_dir_all(&dep).unwrap();
+        std::fs::write(root.join("main.rs"), "fn main() {}").unwrap();
+        std::fs::write(dep.join("lib.rs"), "pub fn dep() {}").unwrap();
+        let nested = git2::Repository::init(&dep).unwrap();
+        commit_all(&nested);
+
+        let setting = |mode| {
+            let mut submodules = HashMap::new();
+            submodules.insert("dep".to_string(), mode);
+            SyncConfig {
+                submodules,
+                ..SyncConfig::default()
+            }
+        };
+        let materialize = SyncConfig::default();
+        let gitlink = setting(SubmoduleMode::Gitlink);
+        let skip = setting(SubmoduleMode::Skip);
+
+        let files = list_non_ignored_files_in_dir(&root, &gitlink).unwrap();
+        assert!(files.contains(&dep));
+        assert!(!files.contains(&dep.join("lib.rs")));
+        assert!(list_non_ignored_files_in_dir(&root, &skip)
+            .unwrap()
+            .iter()
+            .all(|f| !f.starts_with(&dep)));
+
+        let before = (
+            hash_dir(&root, &materialize).await,
+            hash_dir(&root, &gitlink).await,
+            hash_dir(&root, &skip).await,
+        );
+
+        // Moving the submodule to another commit with the same files
+        commit_all(&nested);
+        let moved = (
+            hash_dir(&root, &materialize).await,
+            hash_dir(&root, &gitlink).await,
+            hash_dir(&root, &skip).await,
+        );
+        assert_ne!(moved.0, before.0);
+        assert_ne!(moved.1, before.1);
+        assert_eq!(moved.2, before.2);
+
+        // Changing its files without committing them
+        std::fs::write(dep.join("lib.rs"), "pub fn changed() {}").unwrap();
+        assert_ne!(hash_dir(&root, &materialize).await, moved.0);
+        assert_eq!(hash_dir(&root, &gitlink).await, moved.1);
+        assert_eq!(hash_dir(&root, &skip).await, moved.2);
+    }
+
+    // checks that LFS pointers hash as their content, and follow the lfs setting
+    #[tokio::test]
+    async fn test_hashes_lfs_pointers_as_their_content() {
+        let pulled = TempDir::new(".hash")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        std::fs::write(pulled.path().join("data.bin"), "real content").unwrap();
+        let pulled = hash_dir(&pulled.path().to_path_buf(), &SyncConfig::default()).await;
+
+        let tmp = TempDir::new(".hash")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let root = tmp.path().to_path_buf();
+        git2::Repository::init(&root).unwrap();
+        let oid = "359b365773dbfb3e21cc1196062f477ad27f83bc04aa9dcd4178d924127a5f17";
+        std::fs::write(
+            root.join("data.bin"),
+            format!(
+                "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 12\n",
+                oid
+            ),
+        )
+        .unwrap();
+        let object = root.join(".git/lfs/objects/35/9b").join(oid);
+        create_dir_all(object.parent().unwrap()).unwrap();
+
+        // The right size, but not what the pointer's oid says
+        std::fs::write(&object, "fake content").unwrap();
+        let files = list_non_ignored_files_in_dir(&root, &SyncConfig::default()).unwrap();
+        let err = hash_files(&root, files.clone(), &SyncConfig::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", err).contains("doesn't match the sha256"));
+
+        std::fs::write(&object, "real content").unwrap();
+        assert_eq!(
+            hash_files(&root, files.clone(), &SyncConfig::default())
+                .await
+                .unwrap(),
+            pulled
+        );
+
+        let failing = SyncConfig {
+            lfs: Some(LfsMode::Fail),
+            ..SyncConfig::default()
+        };
+        let err = hash_files(&root, files, &failing).await.err().unwrap();
+        assert!(err.to_string().contains("  data.bin\n"));
+    }
 }
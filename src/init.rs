@@ -39,7 +39,7 @@ pub async fn run_attach(global_config_dir: PathBuf, args: AttachArguments) -> Re
 
     // Check if there's already a project
     let maybe_project = projects.iter().find(|p| p.slug == slug.clone());
-    let project: Result<Project> = match maybe_project {
+    let _project: Result<Project> = match maybe_project {
         Some(p) => {
             if !Confirm::new()
                 .with_prompt(format!(
@@ -69,11 +69,16 @@ pub async fn run_attach(global_config_dir: PathBuf, args: AttachArguments) -> Re
     overwrite_local_config(
         env::current_dir().context("Failed to read current dir")?,
         move |c| LocalConfig {
+            version: c.version,
+            include: c.include,
+            vars: c.vars,
+            templates: c.templates,
             jobs: c.jobs,
             project: Some(ProjectConfig {
                 name: Some(local_slug),
             }),
             containers: c.containers,
+            sync: c.sync,
         },
     )
     .context("Failed to create buildrecall.toml")?;
@@ -81,7 +86,8 @@ pub async fn run_attach(global_config_dir: PathBuf, args: AttachArguments) -> Re
     // create a .git folder for brr to use that doesn't mess with the user's git.
     let g = git::RecallGit::new(global_config_dir.clone())?;
     g.create_shadow_git_folder(slug)
-        .context(format!("Failed to create a shadow git folder (used to sync files without messing with your own git setup) in {:?}/{}", global_config_dir, ".gits"))?;
+        .await
+        .context(format!("Failed to create a shadow git folder (used to sync files without messing with your own git setup) in {:?}", git::shadow_repos_dir(global_config_dir)))?;
 
     Ok(())
 }
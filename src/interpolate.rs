@@ -0,0 +1,183 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+
+use crate::config_local::{EnvValue, JobConfig, LocalConfig};
+
+/// The variables brr sets itself, which [vars] can't
+pub const BUILTIN_VARS: &[&str] = &["project.name", "job.name", "container.name", "tree_hash"];
+
+/// The variables of `job` when it runs in `container`: the built in ones and the
+/// [vars] of buildrecall.toml
+pub fn job_vars(
+    config: &LocalConfig,
+    job: &str,
+    container: &str,
+    tree_hash: &str,
+) -> HashMap<String, String> {
+    let mut vars = config.vars.clone();
+    let project = config.project().name.unwrap_or_default();
+    for (name, value) in BUILTIN_VARS
+        .iter()
+        .zip(&[project.as_str(), job, container, tree_hash])
+    {
+        vars.insert(name.to_string(), value.to_string());
+    }
+    vars
+}
+
+/// Replaces each `${name}` in `text` that's in `vars` with its value. `$${` is a
+/// literal `${`. Every other `${...}` is left as it is for the shell, such as
+/// `${HOME}`, `${1:-debug}` or the `${i}` of a loop in the script.
+pub fn interpolate(text: &str, vars: &HashMap<String, String>) -> Result<String> {
+    Ok(expand(text, vars)?.0)
+}
+
+/// The names in `job` that look like brr variables but aren't in `vars`. They're
+/// left for the shell, but they may be typos.
+pub fn unresolved_job_vars(job: &JobConfig, vars: &HashMap<String, String>) -> Vec<String> {
+    let env = job.env.values().filter_map(|value| match value {
+        EnvValue::AsString(s) => Some(s),
+        EnvValue::AsSecret(_) => None,
+    });
+
+    let mut names: Vec<String> = vec![];
+    for text in std::iter::once(&job.run).chain(&job.artifacts).chain(env) {
+        // Malformed text is reported by `interpolate_job`
+        for name in expand(text, vars).map(|e| e.1).unwrap_or_default() {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names.sort();
+    names
+}
+
+// `text` interpolated, along with the names left for the shell that could have
+// been meant for brr
+fn expand(text: &str, vars: &HashMap<String, String>) -> Result<(String, Vec<String>)> {
+    let mut out = String::with_capacity(text.len());
+    let mut unresolved: Vec<String> = vec![];
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("`{}` is missing its closing `}}`", &rest[start..]))?;
+        let name = rest[start + 2..start + end].trim();
+        match vars.get(name) {
+            Some(value) => out.push_str(value),
+            None => {
+                if !is_for_the_shell(name) && !unresolved.iter().any(|n| n == name) {
+                    unresolved.push(name.to_string());
+                }
+                out.push_str(&rest[start..start + end + 1]);
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+
+    Ok((out, unresolved))
+}
+
+// Upper case variables, ex: ${TARGET_CC}, and parameter expansions, ex:
+// ${1:-debug}, are the shell's
+fn is_for_the_shell(name: &str) -> bool {
+    let ours = name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+    let upper_case = name
+        .chars()
+        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+    !ours || upper_case
+}
+
+/// `job` with its `run`, `artifacts` and plain env values interpolated. Secrets
+/// are only a name and a version, and are left alone.
+pub fn interpolate_job(
+    name: &str,
+    job: &JobConfig,
+    vars: &HashMap<String, String>,
+) -> Result<JobConfig> {
+    let run = interpolate(&job.run, vars).context(format!("In jobs.{}.run", name))?;
+    let artifacts = job
+        .artifacts
+        .iter()
+        .map(|a| interpolate(a, vars))
+        .collect::<Result<Vec<_>>>()
+        .context(format!("In jobs.{}.artifacts", name))?;
+
+    let mut env = HashMap::new();
+    for (key, value) in &job.env {
+        let value = match value {
+            EnvValue::AsString(s) => EnvValue::AsString(
+                interpolate(s, vars).context(format!("In jobs.{}.env.{}", name, key))?,
+            ),
+            secret @ EnvValue::AsSecret(_) => secret.clone(),
+        };
+        env.insert(key.clone(), value);
+    }
+
+    Ok(JobConfig {
+        run,
+        artifacts,
+        env,
+        ..job.clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{interpolate, unresolved_job_vars};
+    use crate::config_local::JobConfig;
+
+    #[test]
+    fn test_interpolates_vars() {
+        let vars: HashMap<String, String> = vec![
+            ("target".to_string(), "x86_64-apple-darwin".to_string()),
+            ("job.name".to_string(), "macos-build".to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            interpolate(
+                "cargo build --target=${target} # ${ job.name }, $${target} ${HOME} ${1:-x}",
+                &vars
+            )
+            .unwrap(),
+            "cargo build --target=x86_64-apple-darwin # macos-build, ${target} ${HOME} ${1:-x}"
+        );
+        // Shell variables that aren't in [vars] are the shell's to expand
+        assert_eq!(
+            interpolate("for i in 1 2; do echo ${i}/${target}; done", &vars).unwrap(),
+            "for i in 1 2; do echo ${i}/x86_64-apple-darwin; done"
+        );
+        assert!(interpolate("${target", &vars).is_err());
+    }
+
+    #[test]
+    fn test_lists_unresolved_vars() {
+        let vars: HashMap<String, String> = vec![("target".to_string(), "x86_64".to_string())]
+            .into_iter()
+            .collect();
+        let job: JobConfig = toml::from_str(
+            "run = \"echo ${out_dir} ${target} ${HOME} ${out_dir}\"\nartifacts = [\"${triple}/brr\"]\n",
+        )
+        .unwrap();
+
+        assert_eq!(unresolved_job_vars(&job, &vars), vec!["out_dir", "triple"]);
+    }
+}
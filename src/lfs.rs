@@ -0,0 +1,363 @@
+use anyhow::{anyhow, Context, Result};
+use crypto::{digest::Digest, sha2::Sha256};
+use git2::{Index, ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use crate::config_local::{LfsMode, SyncConfig};
+
+// Pointer files are around 130 bytes, anything much bigger is real content
+const POINTER_MAX_SIZE: usize = 1024;
+const POINTER_VERSION: &str = "version https://git-lfs.github.com/spec/v1";
+
+// In the shadow repo, which blob each LFS object was imported as, one
+// "<sha256> <blob id>" per line
+const IMPORTED_FILE: &str = "brr-lfs-imported";
+
+/// What Git LFS leaves in the worktree instead of a file's content, when the
+/// content hasn't been downloaded (or smudged) yet
+#[derive(Debug, PartialEq)]
+pub struct Pointer {
+    /// Hex sha256 of the content
+    pub oid: String,
+    pub size: u64,
+}
+
+/// Parses a Git LFS pointer file, see https://github.com/git-lfs/git-lfs/blob/main/docs/spec.md
+pub fn parse_pointer(content: &[u8]) -> Option<Pointer> {
+    if content.len() > POINTER_MAX_SIZE {
+        return None;
+    }
+
+    let text = std::str::from_utf8(content).ok()?;
+    let mut lines = text.lines();
+    if lines.next()? != POINTER_VERSION {
+        return None;
+    }
+
+    let mut oid = None;
+    let mut size = None;
+    for line in lines {
+        if let Some(hash) = line.strip_prefix("oid sha256:") {
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            oid = Some(hash.to_ascii_lowercase());
+        } else if let Some(s) = line.strip_prefix("size ") {
+            size = Some(s.parse().ok()?);
+        }
+    }
+
+    Some(Pointer {
+        oid: oid?,
+        size: size?,
+    })
+}
+
+/// Where the content of the pointer at `file` is, in the local LFS object store of
+/// the git repo `file` belongs to
+pub fn object_path(file: &Path, pointer: &Pointer) -> Result<PathBuf> {
+    let dir = file.parent().unwrap_or(file);
+    let repo = Repository::discover(dir).context(format!(
+        "{:?} is a Git LFS pointer, but isn't in a git repo",
+        file
+    ))?;
+
+    // Linked worktrees share the objects of the main repo
+    let mut git_dir = repo.path().to_path_buf();
+    if let Ok(common) = fs::read_to_string(git_dir.join("commondir")) {
+        git_dir = git_dir.join(common.trim());
+    }
+
+    Ok(git_dir
+        .join("lfs")
+        .join("objects")
+        .join(&pointer.oid[0..2])
+        .join(&pointer.oid[2..4])
+        .join(&pointer.oid))
+}
+
+/// The content of the pointer at `file`, from the local LFS object store
+pub fn read_object(file: &Path, pointer: &Pointer) -> Result<Vec<u8>> {
+    let path = checked_object_path(file, pointer)?;
+    let content =
+        fs::read(&path).context(format!("Failed to read the Git LFS object {:?}", path))?;
+    check_oid(file, &path, pointer, &content[..])?;
+    Ok(content)
+}
+
+/// The error for Git LFS pointers found at `paths` when `[sync] lfs = "fail"`
+pub fn pointers_not_allowed(paths: &[String]) -> anyhow::Error {
+    let listed = paths
+        .iter()
+        .map(|path| format!("  {}\n", path))
+        .collect::<String>();
+    anyhow!(
+        "These files are Git LFS pointers rather than their content:\n{}Run `git lfs pull` to download them, or set lfs = \"resolve\" in the [sync] section of buildrecall.toml to read them from the local LFS store.",
+        listed
+    )
+}
+
+// The store is only checked for the size of its objects, a truncated download or
+// a bad copy could leave the wrong content under the right name
+fn check_oid(file: &Path, path: &Path, pointer: &Pointer, mut content: impl Read) -> Result<()> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let n = content
+            .read(&mut buf)
+            .context(format!("Failed to read the Git LFS object {:?}", path))?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+    }
+
+    if hasher.result_str() != pointer.oid {
+        return Err(anyhow!(
+            "The Git LFS object for {:?} at {:?} doesn't match the sha256 in its pointer, it may be corrupt. Delete it and run `git lfs pull`.",
+            file,
+            path
+        ));
+    }
+    Ok(())
+}
+
+fn checked_object_path(file: &Path, pointer: &Pointer) -> Result<PathBuf> {
+    let path = object_path(file, pointer)?;
+    match fs::metadata(&path) {
+        Ok(meta) if meta.len() == pointer.size => Ok(path),
+        Ok(_) => Err(anyhow!(
+            "The Git LFS object for {:?} at {:?} isn't the size its pointer says, it may be corrupt. Delete it and run `git lfs pull`.",
+            file,
+            path
+        )),
+        Err(_) => Err(anyhow!(
+            "{:?} is a Git LFS pointer and its content isn't in the local LFS store. Run `git lfs pull` to download it.",
+            file
+        )),
+    }
+}
+
+/// Replaces the Git LFS pointers in `tree_oid` with their content from the local
+/// LFS store, or fails when `[sync] lfs = "fail"`. Returns the new tree, or `tree_oid`
+/// when there weren't any.
+pub fn resolve_pointers(
+    repo: &Repository,
+    tree_oid: Oid,
+    worktree: &Path,
+    sync: &SyncConfig,
+) -> Result<Oid> {
+    let pointers = find_pointers(repo, tree_oid)?;
+    if pointers.is_empty() {
+        return Ok(tree_oid);
+    }
+
+    if sync.lfs() == LfsMode::Fail {
+        let paths = pointers
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        return Err(pointers_not_allowed(&paths));
+    }
+
+    let tree = repo.find_tree(tree_oid)?;
+    let mut index = Index::new()?;
+    index.read_tree(&tree)?;
+    let odb = repo.odb()?;
+    let mut imported = read_imported(repo);
+    let mut imported_more = false;
+    for (path, pointer) in pointers {
+        let mut entry = index
+            .get_path(Path::new(&path), 0)
+            .ok_or(anyhow!("{} is missing from the shadow git tree", path))?;
+
+        // Objects can be big, and each `brr run` syncs more than once. Blobs are
+        // named after their content, so one that's still there is still right.
+        entry.id = match imported.get(&pointer.oid).filter(|id| odb.exists(**id)) {
+            Some(id) => *id,
+            None => {
+                let id = import_object(repo, &worktree.join(&path), &pointer)?;
+                imported.insert(pointer.oid.clone(), id);
+                imported_more = true;
+                id
+            }
+        };
+        index.add(&entry)?;
+    }
+
+    if imported_more {
+        write_imported(repo, &imported)?;
+    }
+    Ok(index.write_tree_to(repo)?)
+}
+
+// Checks the object of the pointer at `file` and adds it to the shadow repo
+fn import_object(repo: &Repository, file: &Path, pointer: &Pointer) -> Result<Oid> {
+    let object = checked_object_path(file, pointer)?;
+    let content = fs::File::open(&object)
+        .context(format!("Failed to read the Git LFS object {:?}", object))?;
+    check_oid(file, &object, pointer, content)?;
+    repo.blob_path(&object)
+        .context(format!("Failed to read the Git LFS object {:?}", object))
+}
+
+// Lines that can't be read are left out, they only cost a new import
+fn read_imported(repo: &Repository) -> HashMap<String, Oid> {
+    fs::read_to_string(repo.path().join(IMPORTED_FILE))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (oid, blob) = line.split_once(' ')?;
+            Some((oid.to_string(), Oid::from_str(blob).ok()?))
+        })
+        .collect()
+}
+
+fn write_imported(repo: &Repository, imported: &HashMap<String, Oid>) -> Result<()> {
+    let mut lines = imported
+        .iter()
+        .map(|(oid, blob)| format!("{} {}\n", oid, blob))
+        .collect::<Vec<_>>();
+    lines.sort();
+    let path = repo.path().join(IMPORTED_FILE);
+    fs::write(&path, lines.concat()).context(format!("Failed to write {:?}", path))
+}
+
+fn find_pointers(repo: &Repository, tree_oid: Oid) -> Result<Vec<(String, Pointer)>> {
+    let odb = repo.odb()?;
+    let tree = repo.find_tree(tree_oid)?;
+
+    let mut pointers = vec![];
+    let mut failed = None;
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if entry.kind() != Some(ObjectType::Blob) {
+            return TreeWalkResult::Ok;
+        }
+
+        // Reading the header is cheap, only small blobs are read in full
+        let found = odb.read_header(entry.id()).and_then(|(size, _)| {
+            if size > POINTER_MAX_SIZE {
+                return Ok(None);
+            }
+            Ok(parse_pointer(odb.read(entry.id())?.data()))
+        });
+        match found {
+            Ok(Some(pointer)) => {
+                let path = format!("{}{}", root, entry.name().unwrap_or_default());
+                pointers.push((path, pointer));
+                TreeWalkResult::Ok
+            }
+            Ok(None) => TreeWalkResult::Ok,
+            Err(e) => {
+                failed = Some(e);
+                TreeWalkResult::Abort
+            }
+        }
+    })?;
+    if let Some(e) = failed {
+        return Err(e).context("Failed to read a file in the shadow git repo");
+    }
+
+    Ok(pointers)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use git2::Repository;
+    use std::{fs, path::Path};
+    use tempdir::TempDir;
+
+    use super::{object_path, parse_pointer, read_object, resolve_pointers, Pointer};
+    use crate::config_local::{LfsMode, SyncConfig};
+
+    // The sha256 of "real content"
+    const OID: &str = "359b365773dbfb3e21cc1196062f477ad27f83bc04aa9dcd4178d924127a5f17";
+
+    fn pointer_file() -> String {
+        format!("{}\noid sha256:{}\nsize 12\n", super::POINTER_VERSION, OID)
+    }
+
+    #[test]
+    fn test_parses_pointers() {
+        assert_eq!(
+            parse_pointer(pointer_file().as_bytes()),
+            Some(Pointer {
+                oid: OID.to_string(),
+                size: 12
+            })
+        );
+        assert_eq!(parse_pointer(b"fn main() {}"), None);
+        assert_eq!(
+            parse_pointer(format!("{}\nsize 12\n", super::POINTER_VERSION).as_bytes()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_replaces_pointers_with_their_content() {
+        let tmp = TempDir::new(".lfs")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let worktree = tmp.path().join("project");
+        Repository::init(&worktree).unwrap();
+        fs::create_dir_all(worktree.join("fixtures")).unwrap();
+        fs::write(worktree.join("fixtures/data.bin"), pointer_file()).unwrap();
+
+        let shadow = Repository::init_bare(tmp.path().join("shadow")).unwrap();
+        let pointer_blob = shadow.blob(pointer_file().as_bytes()).unwrap();
+        let mut fixtures = shadow.treebuilder(None).unwrap();
+        fixtures.insert("data.bin", pointer_blob, 0o100644).unwrap();
+        let fixtures = fixtures.write().unwrap();
+        let mut root = shadow.treebuilder(None).unwrap();
+        root.insert("fixtures", fixtures, 0o040000).unwrap();
+        let tree_oid = root.write().unwrap();
+
+        let file = worktree.join("fixtures/data.bin");
+        let pointer = parse_pointer(pointer_file().as_bytes()).unwrap();
+        let err = resolve_pointers(&shadow, tree_oid, &worktree, &SyncConfig::default())
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", err).contains("git lfs pull"));
+
+        let object = object_path(&file, &pointer).unwrap();
+        fs::create_dir_all(object.parent().unwrap()).unwrap();
+        // The right size, but not the right content
+        fs::write(&object, "fake content").unwrap();
+        let err = resolve_pointers(&shadow, tree_oid, &worktree, &SyncConfig::default())
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", err).contains("doesn't match the sha256"));
+        assert!(read_object(&file, &pointer).is_err());
+
+        fs::write(&object, "real content").unwrap();
+        assert_eq!(read_object(&file, &pointer).unwrap(), b"real content");
+
+        let resolved =
+            resolve_pointers(&shadow, tree_oid, &worktree, &SyncConfig::default()).unwrap();
+        let tree = shadow.find_tree(resolved).unwrap();
+        let entry = tree.get_path(Path::new("fixtures/data.bin")).unwrap();
+        assert_eq!(
+            shadow.find_blob(entry.id()).unwrap().content(),
+            b"real content"
+        );
+
+        // Imported once, later syncs don't read the object again
+        fs::remove_file(&object).unwrap();
+        assert_eq!(
+            resolve_pointers(&shadow, tree_oid, &worktree, &SyncConfig::default()).unwrap(),
+            resolved
+        );
+
+        let failing = SyncConfig {
+            lfs: Some(LfsMode::Fail),
+            ..SyncConfig::default()
+        };
+        assert!(resolve_pointers(&shadow, tree_oid, &worktree, &failing).is_err());
+    }
+}
@@ -1,13 +1,30 @@
 pub use crate::{config_global::get_global_config_dir, hash::list_non_ignored_files_in_dir};
 
 pub mod api;
+pub mod bridge;
+pub mod config;
+pub mod config_edit;
 pub mod config_global;
 pub mod config_local;
+pub mod gc;
 pub mod git;
+pub mod guard;
 pub mod hash;
 pub mod init;
+pub mod interpolate;
 pub mod invite;
+pub mod lfs;
+pub mod lock;
 pub mod login;
+pub mod migrate;
+pub mod net;
+pub mod profile;
+pub mod progress;
 pub mod push;
+pub mod resolve;
 pub mod run;
+pub mod schema;
 pub mod secrets;
+pub mod submodules;
+pub mod units;
+pub mod validate;
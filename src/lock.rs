@@ -0,0 +1,200 @@
+use anyhow::{anyhow, Context, Result};
+use fs2::FileExt;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long to wait on another brr process before giving up
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// An inter-process lock on a shadow git repo, held while the repo is created and
+/// while its index and refs are being written. It's an OS advisory lock on a file
+/// next to the repo, so the OS releases it when its owner exits, crashes included.
+/// The file holds the owner's PID, only to say who to wait for.
+/// The lock is released when this is dropped.
+pub struct RepoLock {
+    // Locked for as long as it's open
+    _file: File,
+}
+
+impl RepoLock {
+    /// Locks the shadow repo at `repo_path`, waiting up to `timeout` for another
+    /// process to let go of it. This blocks, use `acquire_async` from async code.
+    pub fn acquire(repo_path: &Path, timeout: Duration) -> Result<RepoLock> {
+        let started = Instant::now();
+        loop {
+            if let Some(lock) = RepoLock::try_acquire(repo_path)? {
+                return Ok(lock);
+            }
+            if started.elapsed() >= timeout {
+                return Err(timed_out(repo_path, timeout));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Same as `acquire`, without holding up a tokio worker while it waits
+    pub async fn acquire_async(repo_path: &Path, timeout: Duration) -> Result<RepoLock> {
+        let started = Instant::now();
+        loop {
+            if let Some(lock) = RepoLock::try_acquire(repo_path)? {
+                return Ok(lock);
+            }
+            if started.elapsed() >= timeout {
+                return Err(timed_out(repo_path, timeout));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    // The lock file lives next to the repo (not in it) so that it can be taken
+    // before the repo exists. None when another process holds it.
+    fn try_acquire(repo_path: &Path) -> Result<Option<RepoLock>> {
+        let path = lock_path(repo_path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .context(format!("Failed to create dir {:?}", parent.to_path_buf()))?;
+        }
+
+        // Never deleted: a process waiting on the old file would lock a file that
+        // nobody else can see
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context(format!("Failed to open the lock file {:?}", path))?;
+        match file.try_lock_exclusive() {
+            Ok(()) => {}
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => return Ok(None),
+            Err(e) => return Err(e).context(format!("Failed to lock {:?}", path)),
+        }
+
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| write!(file, "{}", process::id()))
+            .context(format!("Failed to write to the lock file {:?}", path))?;
+
+        // Only brr touches the shadow repos, and every brr process takes this lock
+        // before writing to the index. So an index.lock that's there now was left
+        // by one that crashed while holding it, and nothing else can be using it.
+        let _ = fs::remove_file(repo_path.join("index.lock"));
+
+        Ok(Some(RepoLock { _file: file }))
+    }
+}
+
+fn timed_out(repo_path: &Path, timeout: Duration) -> anyhow::Error {
+    let path = lock_path(repo_path);
+    let holder = read_holder(&path)
+        .map(|pid| format!("pid {}", pid))
+        .unwrap_or_else(|| "an unknown process".to_string());
+    anyhow!(
+        "Timed out after {}s waiting for the lock on the shadow git repo {:?}, which is held by {}. Wait for it to finish, or stop it and try again.",
+        timeout.as_secs(),
+        repo_path,
+        holder
+    )
+}
+
+fn lock_path(repo_path: &Path) -> PathBuf {
+    let mut name = repo_path
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_default();
+    name.push(".lock");
+    repo_path.with_file_name(name)
+}
+
+// Empty while the holder is between locking and writing its pid
+fn read_holder(path: &Path) -> Option<u32> {
+    let mut pid = String::new();
+    File::open(path).ok()?.read_to_string(&mut pid).ok()?;
+    pid.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process, time::Duration};
+
+    use anyhow::Context;
+    use tempdir::TempDir;
+
+    use super::{lock_path, RepoLock};
+
+    #[test]
+    fn test_lock_is_released_on_drop() {
+        let tmp = TempDir::new(".lock")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let repo = tmp.path().join("my-project");
+
+        {
+            let _lock = RepoLock::acquire(&repo, Duration::from_secs(1)).unwrap();
+            assert!(RepoLock::try_acquire(&repo).unwrap().is_none());
+        }
+
+        let _lock = RepoLock::acquire(&repo, Duration::from_millis(200)).unwrap();
+    }
+
+    #[test]
+    fn test_times_out_when_held_by_a_live_process() {
+        let tmp = TempDir::new(".lock")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let repo = tmp.path().join("my-project");
+
+        let _lock = RepoLock::acquire(&repo, Duration::from_secs(1)).unwrap();
+        let err = RepoLock::acquire(&repo, Duration::from_millis(200))
+            .err()
+            .unwrap();
+
+        assert!(err
+            .to_string()
+            .contains(&format!("held by pid {}", process::id())));
+    }
+
+    #[test]
+    fn test_reclaims_a_stale_lock() {
+        let tmp = TempDir::new(".lock")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let repo = tmp.path().join("my-project");
+
+        // Left behind by a process that crashed, maybe before writing its pid
+        fs::write(lock_path(&repo), "").unwrap();
+
+        let _lock = RepoLock::acquire(&repo, Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            fs::read_to_string(lock_path(&repo)).unwrap(),
+            process::id().to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_waits_without_blocking_the_runtime() {
+        let tmp = TempDir::new(".lock")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let repo = tmp.path().join("my-project");
+
+        let held = RepoLock::acquire(&repo, Duration::from_secs(1)).unwrap();
+        // Only runs if acquire_async yields to it while it waits
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(held);
+        });
+
+        RepoLock::acquire_async(&repo, Duration::from_secs(5))
+            .await
+            .unwrap();
+        release.await.unwrap();
+    }
+}
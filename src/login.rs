@@ -5,9 +5,7 @@ use std::{path::PathBuf, process::Command};
 
 use crate::{
     api::{ApiClient, BuildRecall},
-    config_global::{
-        get_global_config_dir, overwrite_global_config, read_global_config, GlobalConfig,
-    },
+    config_global::{overwrite_global_config, read_global_config, GlobalConfig},
 };
 
 /// Login to
@@ -31,19 +29,18 @@ async fn post_cli_login(global_config: GlobalConfig, single_use_token: String) -
 }
 
 pub async fn run_login(global_config_dir: PathBuf, login: Login) -> Result<()> {
-    let global_config = read_global_config(global_config_dir)?;
+    let global_config = read_global_config(global_config_dir.clone())?;
 
     match login.token {
         Some(token) => {
             let tok = post_cli_login(global_config, token).await?;
 
-            let dir = get_global_config_dir()?;
-            overwrite_global_config(dir, |c| GlobalConfig {
-                connection: Some(ConnectionConfig {
+            // Saved to the profile in use, so that each profile has its own login
+            overwrite_global_config(global_config_dir, |c| {
+                c.update_active_connection(|conn| ConnectionConfig {
                     access_token: Some(tok.clone()),
-                    control_domain: Some(c.control_domain()),
-                    scheduler_domain: Some(c.scheduler_domain()),
-                }),
+                    ..conn
+                })
             })?;
 
             Ok(())
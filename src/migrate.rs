@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Result};
+use toml::value::{Table, Value};
+
+use crate::config_local::CONFIG_FORMAT_VERSION;
+
+// Files from before the `version` field are version 1
+const UNVERSIONED: u32 = 1;
+
+/// A change to the buildrecall.toml format, from `from` to `from + 1`. `apply`
+/// returns whether the file needed it.
+struct Migration {
+    from: u32,
+    description: &'static str,
+    apply: fn(&mut Table) -> Result<bool>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        description: "[[jobs]] and [[containers]] arrays became tables keyed by their name",
+        apply: named_arrays_to_tables,
+    },
+    Migration {
+        from: 2,
+        description: "include, templates, vars and the job limits need version 3",
+        apply: uses_version_3_fields,
+    },
+];
+
+/// The format version `config` is written in
+pub fn version_of(config: &Table) -> Result<u32> {
+    match config.get("version") {
+        None => Ok(UNVERSIONED),
+        Some(Value::Integer(v)) if *v >= 1 => Ok(*v as u32),
+        Some(v) => Err(anyhow!(
+            "`version` should be a number of 1 or more, not {}",
+            v
+        )),
+    }
+}
+
+/// Upgrades a parsed buildrecall.toml to the current format, in place, and stamps
+/// it with the current version. Returns what changed, and leaves it as it is when
+/// nothing needed to, ex: an unversioned file that's already in the current shape.
+pub fn migrate_local_config(config: &mut Table) -> Result<Vec<&'static str>> {
+    let version = version_of(config)?;
+    if version > CONFIG_FORMAT_VERSION {
+        return Err(anyhow!(
+            "buildrecall.toml is in format version {}, but this brr only knows up to version {}. Upgrade brr to use it.",
+            version,
+            CONFIG_FORMAT_VERSION
+        ));
+    }
+    if version == CONFIG_FORMAT_VERSION {
+        return Ok(vec![]);
+    }
+
+    let mut applied = vec![];
+    for migration in MIGRATIONS.iter().filter(|m| m.from >= version) {
+        let changed = (migration.apply)(config).map_err(|e| {
+            anyhow!(
+                "Failed to upgrade buildrecall.toml from format version {}: {:#}",
+                migration.from,
+                e
+            )
+        })?;
+        if changed {
+            applied.push(migration.description);
+        }
+    }
+    if applied.is_empty() {
+        return Ok(applied);
+    }
+    config.insert(
+        "version".to_string(),
+        Value::Integer(CONFIG_FORMAT_VERSION as i64),
+    );
+
+    Ok(applied)
+}
+
+// [[jobs]]
+// name = "build"
+// run = "..."
+//
+// to
+//
+// [jobs.build]
+// run = "..."
+fn named_arrays_to_tables(config: &mut Table) -> Result<bool> {
+    let mut changed = false;
+    for section in &["jobs", "containers"] {
+        let items = match config.get(*section) {
+            Some(Value::Array(items)) => items.clone(),
+            _ => continue,
+        };
+
+        let mut by_name = Table::new();
+        for (i, item) in items.into_iter().enumerate() {
+            let mut item = match item {
+                Value::Table(t) => t,
+                _ => return Err(anyhow!("{}[{}] should be a table", section, i)),
+            };
+            let name = match item.remove("name") {
+                Some(Value::String(name)) => name,
+                _ => return Err(anyhow!("{}[{}] is missing its `name`", section, i)),
+            };
+            if by_name.insert(name.clone(), Value::Table(item)).is_some() {
+                return Err(anyhow!("there are two {} named '{}'", section, name));
+            }
+        }
+        config.insert(section.to_string(), Value::Table(by_name));
+        changed = true;
+    }
+    Ok(changed)
+}
+
+// Nothing in version 2 changed meaning, but files that use what version 3 added are
+// stamped with it, so that an older brr asks to be upgraded rather than failing
+// on fields it doesn't know
+fn uses_version_3_fields(config: &mut Table) -> Result<bool> {
+    if ["include", "templates", "vars"]
+        .iter()
+        .any(|key| config.contains_key(*key))
+    {
+        return Ok(true);
+    }
+
+    let jobs = match config.get("jobs") {
+        Some(Value::Table(jobs)) => jobs,
+        _ => return Ok(false),
+    };
+    let job_fields = [
+        "extends", "timeout", "retries", "retry_on", "cpus", "memory",
+    ];
+    Ok(jobs.values().any(|job| match job {
+        Value::Table(job) => job_fields.iter().any(|field| job.contains_key(*field)),
+        _ => false,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use std::fs;
+    use tempdir::TempDir;
+
+    use super::migrate_local_config;
+    use crate::config_local::{
+        overwrite_local_config, read_local_config, LocalConfig, CONFIG_FORMAT_VERSION,
+        LOCAL_CONFIG_NAME,
+    };
+
+    #[test]
+    fn test_reads_the_jobs_array_form() {
+        let mut old: toml::value::Table = toml::from_str(
+            "[project]\nname = 'app'\n\n[[jobs]]\nname = \"mybuild\"\nrun = \"cargo build\"\nartifacts = [\"target\"]\n",
+        )
+        .unwrap();
+
+        let applied = migrate_local_config(&mut old).unwrap();
+        assert_eq!(applied.len(), 1);
+
+        let config: LocalConfig = toml::Value::Table(old).try_into().unwrap();
+        assert_eq!(config.version, Some(CONFIG_FORMAT_VERSION));
+        assert_eq!(config.jobs["mybuild"].run, "cargo build");
+        assert_eq!(config.jobs["mybuild"].artifacts, vec!["target"]);
+    }
+
+    #[test]
+    fn test_refuses_newer_versions() {
+        let mut newer: toml::value::Table = toml::from_str("version = 99\n").unwrap();
+        assert!(migrate_local_config(&mut newer).is_err());
+
+        let mut current: toml::value::Table =
+            toml::from_str("[jobs.build]\nrun = \"make\"\n").unwrap();
+        assert!(migrate_local_config(&mut current).unwrap().is_empty());
+        assert!(!current.contains_key("version"));
+    }
+
+    #[test]
+    fn test_stamps_files_that_use_version_3() {
+        let mut old: toml::value::Table =
+            toml::from_str("version = 2\n\n[jobs.build]\nrun = \"make\"\n").unwrap();
+        assert!(migrate_local_config(&mut old).unwrap().is_empty());
+        assert_eq!(old["version"].as_integer(), Some(2));
+
+        let mut newer: toml::value::Table =
+            toml::from_str("version = 2\n\n[jobs.build]\nrun = \"make\"\ntimeout = \"45m\"\n")
+                .unwrap();
+        let applied = migrate_local_config(&mut newer).unwrap();
+        assert_eq!(
+            applied,
+            vec!["include, templates, vars and the job limits need version 3"]
+        );
+        assert_eq!(newer["version"].as_integer(), Some(3));
+        let config: LocalConfig = toml::Value::Table(newer).try_into().unwrap();
+        assert_eq!(config.jobs["build"].timeout.unwrap().0.as_secs(), 45 * 60);
+    }
+
+    #[test]
+    fn test_overwriting_upgrades_the_file() {
+        let tmp = TempDir::new(".migrate")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        fs::write(
+            tmp.path().join(LOCAL_CONFIG_NAME),
+            "# Shared with the team\n[project]\nname = \"app\"\n\n[[jobs]]\nname = \"build\"\nrun = \"make\"\n",
+        )
+        .unwrap();
+
+        overwrite_local_config(tmp.path().to_path_buf(), |c| c).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(tmp.path().join(LOCAL_CONFIG_NAME)).unwrap(),
+            "version = 3\n# Shared with the team\n[project]\nname = \"app\"\n\n[jobs.build]\nrun = \"make\"\n"
+        );
+        let config = read_local_config(tmp.path().to_path_buf()).unwrap();
+        assert_eq!(config.jobs["build"].run, "make");
+    }
+}
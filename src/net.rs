@@ -0,0 +1,437 @@
+use anyhow::{anyhow, Context, Result};
+use percent_encoding::percent_decode_str;
+use std::{env, fs, path::PathBuf};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use url::Url;
+
+use crate::config_global::{GlobalConfig, Setting};
+
+// Proxies answer a CONNECT with a short header, anything longer is a misbehaving proxy
+const MAX_PROXY_RESPONSE: usize = 8 * 1024;
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+/// A connection that may or may not be TLS
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// The proxy and TLS settings from the `[connection]` section of the global config.
+/// Everything brr sends goes through them: API calls, the pull websocket and the
+/// git transport.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkConfig {
+    /// An http:// proxy, tunneled through with CONNECT
+    pub proxy: Option<Url>,
+    /// Hosts to reach without the proxy, from `$NO_PROXY`
+    pub no_proxy: Vec<String>,
+    /// PEM root certificates to trust, on top of the system's
+    pub ca_bundle: Option<PathBuf>,
+    /// A PKCS#12 client certificate and its password
+    pub client_cert: Option<PathBuf>,
+    pub client_cert_password: Option<String>,
+}
+
+impl NetworkConfig {
+    /// Reads the settings from `config`, see `Setting` for where they can come from.
+    /// The proxy defaults to `$HTTPS_PROXY`, and skips the hosts in `$NO_PROXY`.
+    pub fn from_config(config: &GlobalConfig) -> Result<NetworkConfig> {
+        let proxy = config
+            .setting(Setting::Proxy)
+            .map(|p| parse_proxy(&p))
+            .transpose()?;
+
+        let no_proxy = ["NO_PROXY", "no_proxy"]
+            .iter()
+            .find_map(|var| env::var(var).ok())
+            .map(|list| parse_no_proxy(&list))
+            .unwrap_or_default();
+
+        Ok(NetworkConfig {
+            proxy,
+            no_proxy,
+            ca_bundle: config.setting(Setting::CaBundle).map(PathBuf::from),
+            client_cert: config.setting(Setting::ClientCert).map(PathBuf::from),
+            client_cert_password: config.setting(Setting::ClientCertPassword),
+        })
+    }
+
+    fn ca_certificates(&self) -> Result<Vec<Vec<u8>>> {
+        let path = match &self.ca_bundle {
+            Some(p) => p,
+            None => return Ok(vec![]),
+        };
+
+        let bundle =
+            fs::read_to_string(path).context(format!("Can't read the CA bundle {:?}", path))?;
+        let certs = split_pem_bundle(&bundle);
+        if certs.is_empty() {
+            return Err(anyhow!(
+                "The CA bundle {:?} has no certificates in it",
+                path
+            ));
+        }
+        Ok(certs)
+    }
+
+    fn client_identity(&self) -> Result<Option<(Vec<u8>, String)>> {
+        let path = match &self.client_cert {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+
+        let der =
+            fs::read(path).context(format!("Can't read the client certificate {:?}", path))?;
+        Ok(Some((
+            der,
+            self.client_cert_password.clone().unwrap_or_default(),
+        )))
+    }
+
+    /// The proxy to reach `host` through, if any
+    pub fn proxy_for(&self, host: &str) -> Option<&Url> {
+        match &self.proxy {
+            Some(_) if bypasses_proxy(&self.no_proxy, host) => None,
+            proxy => proxy.as_ref(),
+        }
+    }
+
+    // What both kinds of reqwest clients need: the proxy and TLS settings
+    fn client_settings(&self) -> Result<ClientSettings> {
+        // Only the configured proxy, not whatever reqwest finds in the environment
+        let proxy = self.proxy.clone().map(|proxy| {
+            let network = self.clone();
+            reqwest::Proxy::custom(move |url| match url.host_str() {
+                Some(host) if bypasses_proxy(&network.no_proxy, host) => None,
+                _ => Some(proxy.clone()),
+            })
+        });
+        let certificates = self
+            .ca_certificates()?
+            .iter()
+            .map(|pem| reqwest::Certificate::from_pem(pem))
+            .collect::<Result<Vec<_>, _>>()?;
+        let identity = match self.client_identity()? {
+            Some((der, password)) => Some(reqwest::Identity::from_pkcs12_der(&der, &password)?),
+            None => None,
+        };
+
+        Ok(ClientSettings {
+            proxy,
+            certificates,
+            identity,
+        })
+    }
+
+    /// An HTTP client for API calls
+    pub fn http_client(&self) -> Result<reqwest::Client> {
+        let settings = self.client_settings()?;
+        let mut builder = reqwest::Client::builder().no_proxy();
+        if let Some(proxy) = settings.proxy {
+            builder = builder.proxy(proxy);
+        }
+        for cert in settings.certificates {
+            builder = builder.add_root_certificate(cert);
+        }
+        if let Some(identity) = settings.identity {
+            builder = builder.identity(identity);
+        }
+
+        builder.build().context("Failed to create an HTTP client")
+    }
+
+    /// Same as `http_client`, for use outside of async code
+    pub fn blocking_http_client(&self) -> Result<reqwest::blocking::Client> {
+        let settings = self.client_settings()?;
+        let mut builder = reqwest::blocking::Client::builder().no_proxy();
+        if let Some(proxy) = settings.proxy {
+            builder = builder.proxy(proxy);
+        }
+        for cert in settings.certificates {
+            builder = builder.add_root_certificate(cert);
+        }
+        if let Some(identity) = settings.identity {
+            builder = builder.identity(identity);
+        }
+
+        builder.build().context("Failed to create an HTTP client")
+    }
+
+    pub fn tls_connector(&self) -> Result<native_tls::TlsConnector> {
+        let mut builder = native_tls::TlsConnector::builder();
+        for pem in self.ca_certificates()? {
+            builder.add_root_certificate(native_tls::Certificate::from_pem(&pem)?);
+        }
+        if let Some((der, password)) = self.client_identity()? {
+            builder.identity(
+                native_tls::Identity::from_pkcs12(&der, &password)
+                    .context("Failed to read the client certificate, is it PKCS#12?")?,
+            );
+        }
+
+        builder.build().context("Failed to set up TLS")
+    }
+
+    /// Opens a TCP connection to the host of `url`, through the proxy if there is one
+    pub async fn connect(&self, url: &Url) -> Result<TcpStream> {
+        let host = url
+            .host_str()
+            .ok_or(anyhow!("{} doesn't have a host", url))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or(anyhow!("{} doesn't have a port", url))?;
+
+        match self.proxy_for(host) {
+            Some(proxy) => tunnel(proxy, host, port).await,
+            None => TcpStream::connect((host, port))
+                .await
+                .context(format!("Failed to connect to {}:{}", host, port)),
+        }
+    }
+
+    /// Like `connect`, with TLS on top for https:// and wss:// URLs
+    pub async fn connect_tls(&self, url: &Url) -> Result<Box<dyn Stream>> {
+        let tcp = self.connect(url).await?;
+        match url.scheme() {
+            "https" | "wss" => {
+                let host = url.host_str().unwrap_or_default();
+                let tls = tokio_native_tls::TlsConnector::from(self.tls_connector()?)
+                    .connect(host, tcp)
+                    .await
+                    .context(format!("Failed to set up TLS with {}", host))?;
+                Ok(Box::new(tls))
+            }
+            _ => Ok(Box::new(tcp)),
+        }
+    }
+}
+
+// The builders of reqwest's async and blocking clients don't share a trait
+struct ClientSettings {
+    proxy: Option<reqwest::Proxy>,
+    certificates: Vec<reqwest::Certificate>,
+    identity: Option<reqwest::Identity>,
+}
+
+// ex: "localhost,.corp.example.com,10.0.0.5:8080", ports are ignored
+fn parse_no_proxy(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|entry| entry.trim())
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let host = match entry.rsplit_once(':') {
+                Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+                _ => entry,
+            };
+            host.trim_start_matches('.').to_ascii_lowercase()
+        })
+        .collect()
+}
+
+// Like curl: "*" is every host, and a domain is itself and its subdomains
+fn bypasses_proxy(no_proxy: &[String], host: &str) -> bool {
+    let host = host
+        .trim_matches(|c| c == '[' || c == ']')
+        .to_ascii_lowercase();
+    no_proxy.iter().any(|entry| {
+        entry == "*"
+            || host == *entry
+            || (host.ends_with(entry.as_str()) && host[..host.len() - entry.len()].ends_with('.'))
+    })
+}
+
+fn parse_proxy(proxy: &str) -> Result<Url> {
+    // Like curl, a bare host:port means http
+    let with_scheme = if proxy.contains("://") {
+        proxy.to_string()
+    } else {
+        format!("http://{}", proxy)
+    };
+
+    let url = Url::parse(&with_scheme).context(format!("'{}' is not a valid proxy URL", proxy))?;
+    if url.scheme() != "http" {
+        return Err(anyhow!(
+            "The proxy '{}' isn't supported, only http:// proxies are",
+            proxy
+        ));
+    }
+    Ok(url)
+}
+
+// Asks the proxy for a raw TCP tunnel to host:port, see RFC 7231 section 4.3.6
+async fn tunnel(proxy: &Url, host: &str, port: u16) -> Result<TcpStream> {
+    let proxy_host = proxy.host_str().unwrap_or_default();
+    let proxy_port = proxy.port_or_known_default().unwrap_or(80);
+    let mut stream = TcpStream::connect((proxy_host, proxy_port))
+        .await
+        .context(format!(
+            "Failed to connect to the proxy {}:{}",
+            proxy_host, proxy_port
+        ))?;
+
+    let mut request = format!(
+        "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+        host = host,
+        port = port
+    );
+    if !proxy.username().is_empty() {
+        let credentials = format!(
+            "{}:{}",
+            percent_decode_str(proxy.username()).decode_utf8_lossy(),
+            percent_decode_str(proxy.password().unwrap_or_default()).decode_utf8_lossy()
+        );
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::encode(credentials)
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // One byte at a time, so that nothing past the header is taken from the tunnel
+    let mut response = vec![];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > MAX_PROXY_RESPONSE {
+            return Err(anyhow!("The proxy {} sent too long a response", proxy_host));
+        }
+        let byte = stream.read_u8().await.context(format!(
+            "The proxy {} closed the connection to {}:{}",
+            proxy_host, host, port
+        ))?;
+        response.push(byte);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+        return Err(anyhow!(
+            "The proxy {} refused to connect to {}:{}: {}",
+            proxy_host,
+            host,
+            port,
+            status_line
+        ));
+    }
+
+    Ok(stream)
+}
+
+fn split_pem_bundle(bundle: &str) -> Vec<Vec<u8>> {
+    let mut certs = vec![];
+    let mut rest = bundle;
+    while let Some(start) = rest.find(PEM_BEGIN) {
+        let end = match rest[start..].find(PEM_END) {
+            Some(e) => start + e + PEM_END.len(),
+            None => break,
+        };
+        certs.push(rest.as_bytes()[start..end].to_vec());
+        rest = &rest[end..];
+    }
+    certs
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{parse_no_proxy, parse_proxy, split_pem_bundle, NetworkConfig};
+
+    #[test]
+    fn test_splits_pem_bundles() {
+        let bundle = "# corp root\n-----BEGIN CERTIFICATE-----\nAAA\n-----END CERTIFICATE-----\n\n-----BEGIN CERTIFICATE-----\nBBB\n-----END CERTIFICATE-----\n";
+        let certs = split_pem_bundle(bundle);
+
+        assert_eq!(certs.len(), 2);
+        assert_eq!(
+            String::from_utf8_lossy(&certs[1]),
+            "-----BEGIN CERTIFICATE-----\nBBB\n-----END CERTIFICATE-----"
+        );
+    }
+
+    #[test]
+    fn test_parses_proxies() {
+        assert_eq!(
+            parse_proxy("proxy.corp:3128").unwrap().as_str(),
+            "http://proxy.corp:3128/"
+        );
+        assert!(parse_proxy("socks5://proxy.corp:1080").is_err());
+    }
+
+    #[test]
+    fn test_skips_the_proxy_for_no_proxy_hosts() {
+        let network = NetworkConfig {
+            proxy: Some(parse_proxy("proxy.corp:3128").unwrap()),
+            no_proxy: parse_no_proxy(" localhost, .corp.example.com,10.0.0.5:8080,"),
+            ..NetworkConfig::default()
+        };
+
+        for host in &[
+            "localhost",
+            "ci.corp.example.com",
+            "corp.example.com",
+            "10.0.0.5",
+        ] {
+            assert!(network.proxy_for(host).is_none(), "{} uses the proxy", host);
+        }
+        for host in &[
+            "scheduler.buildrecall.com",
+            "notcorp.example.com",
+            "10.0.0.50",
+        ] {
+            assert!(
+                network.proxy_for(host).is_some(),
+                "{} skips the proxy",
+                host
+            );
+        }
+
+        let everything = NetworkConfig {
+            no_proxy: parse_no_proxy("*"),
+            ..network
+        };
+        assert!(everything.proxy_for("scheduler.buildrecall.com").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tunnels_through_the_proxy() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = proxy.local_addr().unwrap();
+
+        let fake_proxy = tokio::spawn(async move {
+            let (mut conn, _) = proxy.accept().await.unwrap();
+            let mut request = vec![];
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(conn.read_u8().await.unwrap());
+            }
+            conn.write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let network = NetworkConfig {
+            proxy: Some(parse_proxy(&format!("http://me:p%40ss@{}", addr)).unwrap()),
+            ..NetworkConfig::default()
+        };
+        let mut stream = network
+            .connect(&"wss://scheduler.buildrecall.com/pull".parse().unwrap())
+            .await
+            .unwrap();
+
+        let mut greeting = [0; 5];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hello");
+
+        let request = fake_proxy.await.unwrap();
+        assert!(request.starts_with("CONNECT scheduler.buildrecall.com:443 HTTP/1.1\r\n"));
+        // base64 of "me:p@ss"
+        assert!(request.contains("Proxy-Authorization: Basic bWU6cEBzcw==\r\n"));
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{anyhow, Result};
+use clap::Clap;
+use std::path::PathBuf;
+
+use crate::config_global::{
+    overwrite_global_config, read_global_config, ConnectionConfig, Endpoint, DEFAULT_PROFILE,
+};
+
+/// Adds a profile, ex: for a staging scheduler. Log in to it with
+/// `BUILDRECALL_PROFILE=<name> brr login`, or after `brr profile use <name>`.
+#[derive(Clap, Debug)]
+pub struct Add {
+    pub name: String,
+
+    /// The app to connect to, a domain or a full URL
+    #[clap(long)]
+    pub control_domain: Option<String>,
+
+    /// The build farm scheduler to connect to, a domain or a full URL
+    #[clap(long)]
+    pub scheduler_domain: Option<String>,
+
+    /// Use plain http:// and ws:// for bare domains
+    #[clap(long)]
+    pub insecure: bool,
+
+    /// Switch to the new profile
+    #[clap(long = "use")]
+    pub switch: bool,
+}
+
+/// Switches to another profile, for every command after this one.
+/// BUILDRECALL_PROFILE overrides it for a single command.
+#[derive(Clap, Debug)]
+pub struct Use {
+    pub name: String,
+}
+
+#[derive(Clap, Debug)]
+pub enum ProfileSubCommand {
+    /// Lists the profiles, the one in use is marked with a '*'
+    #[clap()]
+    List,
+
+    #[clap()]
+    Use(Use),
+
+    #[clap()]
+    Add(Add),
+}
+
+pub async fn run_profile(global_config_dir: PathBuf, cmd: ProfileSubCommand) -> Result<()> {
+    match cmd {
+        ProfileSubCommand::List => {
+            let config = read_global_config(global_config_dir)?;
+            let active = config.active_profile();
+            for name in config.profile_names() {
+                let marker = if name == active { "*" } else { " " };
+                println!("{} {}", marker, name);
+            }
+            Ok(())
+        }
+        ProfileSubCommand::Use(u) => {
+            overwrite_global_config(global_config_dir, |mut c| {
+                c.current_profile = if u.name == DEFAULT_PROFILE {
+                    None
+                } else {
+                    Some(u.name.clone())
+                };
+                c
+            })?;
+            eprintln!("Using the '{}' profile", u.name);
+            Ok(())
+        }
+        ProfileSubCommand::Add(a) => {
+            if a.name == DEFAULT_PROFILE {
+                return Err(anyhow!(
+                    "'{}' is the [connection] section of the config, pick another name",
+                    DEFAULT_PROFILE
+                ));
+            }
+
+            let insecure = if a.insecure { Some(true) } else { None };
+            for domain in a.control_domain.iter().chain(a.scheduler_domain.iter()) {
+                Endpoint::parse(domain, insecure)?;
+            }
+
+            // Not read beforehand, the profile may be missing because it's in
+            // $BUILDRECALL_PROFILE before it's added
+            let mut exists = false;
+            overwrite_global_config(global_config_dir.clone(), |mut c| {
+                if c.profiles.contains_key(&a.name) {
+                    exists = true;
+                    return c;
+                }
+                c.profiles.insert(
+                    a.name.clone(),
+                    ConnectionConfig {
+                        control_domain: a.control_domain.clone(),
+                        scheduler_domain: a.scheduler_domain.clone(),
+                        insecure,
+                        ..ConnectionConfig::default()
+                    },
+                );
+                if a.switch {
+                    c.current_profile = Some(a.name.clone());
+                }
+                c
+            })?;
+            if exists {
+                return Err(anyhow!(
+                    "There's already a profile named '{}', edit it in {:?}",
+                    a.name,
+                    global_config_dir.join("config.toml")
+                ));
+            }
+
+            eprintln!("Added the '{}' profile", a.name);
+            Ok(())
+        }
+    }
+}
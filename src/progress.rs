@@ -0,0 +1,122 @@
+use git2::PackBuilderStage;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::Cell,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crate::units::format_bytes;
+
+// Redrawing on every callback slows the push down on big packs
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What a push to the farm sent over the wire
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PushSummary {
+    pub tree_hash: String,
+    pub objects: usize,
+    pub bytes: usize,
+    pub duration_ms: u128,
+}
+
+impl PushSummary {
+    pub fn describe(&self) -> String {
+        format!(
+            "Pushed {} objects ({}) in {:.1}s",
+            self.objects,
+            format_bytes(self.bytes as u64),
+            self.duration_ms as f64 / 1000.0
+        )
+    }
+}
+
+/// Tracks a git push and draws its progress on stderr, when stderr is a terminal.
+/// Meant to be shared by the `pack_progress` and `push_transfer_progress` callbacks.
+pub struct PushProgress {
+    started: Instant,
+    last_drawn: Cell<Option<Instant>>,
+    interactive: bool,
+    objects: Cell<usize>,
+    bytes: Cell<usize>,
+}
+
+impl PushProgress {
+    pub fn new() -> PushProgress {
+        PushProgress {
+            started: Instant::now(),
+            last_drawn: Cell::new(None),
+            interactive: atty::is(atty::Stream::Stderr),
+            objects: Cell::new(0),
+            bytes: Cell::new(0),
+        }
+    }
+
+    pub fn pack(&self, stage: PackBuilderStage, current: usize, total: usize) {
+        let stage = match stage {
+            PackBuilderStage::AddingObjects => "Counting objects",
+            PackBuilderStage::Deltafication => "Compressing objects",
+        };
+        self.draw(
+            || format!("{}: {}/{}", stage, current, total),
+            current == total,
+        );
+    }
+
+    pub fn transfer(&self, current: usize, total: usize, bytes: usize) {
+        self.objects.set(current);
+        self.bytes.set(bytes);
+        self.draw(
+            || {
+                format!(
+                    "Uploading: {}/{} objects, {}",
+                    current,
+                    total,
+                    format_bytes(bytes as u64)
+                )
+            },
+            current == total,
+        );
+    }
+
+    /// Clears the progress line and returns what was sent
+    pub fn finish(&self, tree_hash: String) -> PushSummary {
+        if self.interactive && self.last_drawn.get().is_some() {
+            eprint!("\r\x1b[2K");
+            let _ = io::stderr().flush();
+        }
+
+        PushSummary {
+            tree_hash,
+            objects: self.objects.get(),
+            bytes: self.bytes.get(),
+            duration_ms: self.started.elapsed().as_millis(),
+        }
+    }
+
+    fn draw(&self, line: impl FnOnce() -> String, force: bool) {
+        if !self.interactive {
+            return;
+        }
+
+        let now = Instant::now();
+        let due = self
+            .last_drawn
+            .get()
+            .map(|t| now.duration_since(t) >= REDRAW_INTERVAL)
+            .unwrap_or(true);
+        if !due && !force {
+            return;
+        }
+
+        self.last_drawn.set(Some(now));
+        eprint!("\r\x1b[2K{}", line());
+        let _ = io::stderr().flush();
+    }
+}
+
+impl Default for PushProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,18 +1,24 @@
-use anyhow::{anyhow, Context, Result};
-use std::{env, path::PathBuf};
+use anyhow::{Context, Result};
+use std::path::PathBuf;
 
-use crate::{git::RecallGit, run::JobArgs};
+use crate::{gc, git::RecallGit, progress::PushSummary, run::JobArgs};
 
 pub async fn run_push_in_current_dir_retry(
     global_config_dir: PathBuf,
     slug: String,
     job_args: JobArgs,
-) -> Result<()> {
-    let g = RecallGit::new(global_config_dir).context("Failed to create shadow git")?;
+) -> Result<PushSummary> {
+    let g = RecallGit::new(global_config_dir.clone()).context("Failed to create shadow git")?;
 
-    g.push_project(slug, true, job_args)
+    let summary = g
+        .push_project(slug.clone(), true, job_args)
         .await
         .context("Failed to push to shadow git repo")?;
 
-    Ok(())
+    // The push already went through, so a failed collection shouldn't fail the run
+    if let Err(e) = gc::auto_gc(global_config_dir, slug).await {
+        tracing::warn!("Failed to collect the shadow git repo: {:?}", e);
+    }
+
+    Ok(summary)
 }
@@ -0,0 +1,285 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::config_local::{Container, JobConfig, JobTemplate, LocalConfig};
+
+/// What a file in `include` can add to buildrecall.toml
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct IncludedConfig {
+    #[serde(default)]
+    vars: HashMap<String, String>,
+    #[serde(default)]
+    templates: HashMap<String, JobTemplate>,
+    #[serde(default)]
+    jobs: HashMap<String, JobConfig>,
+    #[serde(default)]
+    containers: HashMap<String, Container>,
+}
+
+/// Turns buildrecall.toml as it's written in `dir` into the jobs brr runs: the
+/// files in `include` are merged in, then each job is merged with the template it
+/// `extends`. Jobs whose template can't be applied are left out, so that one broken
+/// job doesn't stop the others from running. `brr validate` reports them.
+pub fn resolve_local_config(dir: &Path, config: LocalConfig) -> Result<LocalConfig> {
+    let mut config = merge_includes(dir, config)?;
+
+    let mut jobs = HashMap::new();
+    for (name, job) in config.jobs.drain() {
+        if let Ok(job) = resolve_job(&config.templates, &name, job) {
+            jobs.insert(name, job);
+        }
+    }
+    config.jobs = jobs;
+
+    Ok(config)
+}
+
+/// Merges the files in `include`, relative to `dir`, into `config`
+pub fn merge_includes(dir: &Path, config: LocalConfig) -> Result<LocalConfig> {
+    let mut config = config;
+    for path in included_files(dir, &config.include)? {
+        let text = fs::read_to_string(&path).context(format!("Can't read path {:?}", path))?;
+        let included: IncludedConfig =
+            toml::from_str(&text).context(format!("Failed to parse {:?}", path))?;
+
+        merge_named(&mut config.vars, included.vars, "var", &path)?;
+        merge_named(&mut config.templates, included.templates, "template", &path)?;
+        merge_named(&mut config.jobs, included.jobs, "job", &path)?;
+        merge_named(
+            &mut config.containers,
+            included.containers,
+            "container",
+            &path,
+        )?;
+    }
+    Ok(config)
+}
+
+/// The job `name` merged with the template it `extends`, if any
+pub fn resolve_job(
+    templates: &HashMap<String, JobTemplate>,
+    name: &str,
+    job: JobConfig,
+) -> Result<JobConfig> {
+    match &job.extends {
+        Some(template) => {
+            let template = flatten_template(templates, template, &mut vec![]).context(format!(
+                "Failed to apply the template of the job `{}`",
+                name
+            ))?;
+            Ok(apply_template(&template, job))
+        }
+        None => Ok(job),
+    }
+}
+
+// The matches of each glob, in order, relative to `dir`
+fn included_files(dir: &Path, patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for pattern in patterns {
+        let full = dir.join(pattern);
+        let full = full
+            .to_str()
+            .ok_or(anyhow!("include `{}` isn't valid UTF-8", pattern))?;
+        let mut matches = glob::glob(full)
+            .context(format!("include `{}` isn't a valid glob", pattern))?
+            .collect::<Result<Vec<_>, _>>()
+            .context(format!("Failed to list the files of include `{}`", pattern))?;
+        if matches.is_empty() {
+            return Err(anyhow!("include `{}` doesn't match any files", pattern));
+        }
+        matches.sort();
+        for path in matches {
+            if !files.contains(&path) {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn merge_named<T>(
+    into: &mut HashMap<String, T>,
+    from: HashMap<String, T>,
+    kind: &str,
+    path: &Path,
+) -> Result<()> {
+    for (name, item) in from {
+        if into.contains_key(&name) {
+            return Err(anyhow!(
+                "There's already a {} named `{}`, so {:?} can't define it again",
+                kind,
+                name,
+                path
+            ));
+        }
+        into.insert(name, item);
+    }
+    Ok(())
+}
+
+// A template merged with everything it extends, with `extends` left empty
+fn flatten_template(
+    templates: &HashMap<String, JobTemplate>,
+    name: &str,
+    seen: &mut Vec<String>,
+) -> Result<JobTemplate> {
+    if seen.iter().any(|s| s == name) {
+        seen.push(name.to_string());
+        return Err(anyhow!(
+            "The templates extend each other in a loop: {}",
+            seen.join(" -> ")
+        ));
+    }
+    seen.push(name.to_string());
+
+    let template = templates.get(name).ok_or_else(|| {
+        let mut names: Vec<_> = templates.keys().map(|n| format!("`{}`", n)).collect();
+        names.sort();
+        anyhow!(
+            "There's no template named `{}`{}",
+            name,
+            if names.is_empty() {
+                ", add a [templates.<name>] for it".to_string()
+            } else {
+                format!(", expected one of {}", names.join(", "))
+            }
+        )
+    })?;
+    let base = match &template.extends {
+        Some(parent) => flatten_template(templates, parent, seen)?,
+        None => JobTemplate::default(),
+    };
+
+    let mut env = base.env;
+    env.extend(template.env.clone());
+    Ok(JobTemplate {
+        extends: None,
+        // Wrapped around the prefix and suffix of what it extends
+        run_prefix: join_scripts(&[base.run_prefix, template.run_prefix.clone()]),
+        run_suffix: join_scripts(&[template.run_suffix.clone(), base.run_suffix]),
+        artifacts: merge_artifacts(&base.artifacts, &template.artifacts),
+        env,
+    })
+}
+
+fn apply_template(template: &JobTemplate, job: JobConfig) -> JobConfig {
+    let run = join_scripts(&[
+        template.run_prefix.clone(),
+        Some(job.run.clone()),
+        template.run_suffix.clone(),
+    ])
+    .unwrap_or_default();
+
+    let mut env = template.env.clone();
+    env.extend(job.env);
+    JobConfig {
+        // Already applied, so that the job stands on its own
+        extends: None,
+        run,
+        artifacts: merge_artifacts(&template.artifacts, &job.artifacts),
+        env,
+        ..job
+    }
+}
+
+// One script after the other, each on its own lines
+fn join_scripts(parts: &[Option<String>]) -> Option<String> {
+    let parts: Vec<&str> = parts
+        .iter()
+        .flatten()
+        .map(|p| p.trim_end_matches('\n'))
+        .filter(|p| !p.trim().is_empty())
+        .collect();
+    if parts.is_empty() {
+        return None;
+    }
+    Some(parts.join("\n") + "\n")
+}
+
+fn merge_artifacts(base: &[String], more: &[String]) -> Vec<String> {
+    let mut artifacts = base.to_vec();
+    for a in more {
+        if !artifacts.contains(a) {
+            artifacts.push(a.clone());
+        }
+    }
+    artifacts
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use std::fs;
+    use tempdir::TempDir;
+
+    use super::{resolve_job, resolve_local_config};
+    use crate::config_local::{EnvValue, LocalConfig};
+
+    #[test]
+    fn test_merges_includes_and_templates() {
+        let tmp = TempDir::new(".resolve")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        fs::create_dir_all(tmp.path().join("ci/jobs")).unwrap();
+        fs::write(
+            tmp.path().join("ci/jobs/release.toml"),
+            "[jobs.release]\nextends = \"rust-release\"\nrun = \"cargo build --release\"\nartifacts = [\"dist\"]\nenv = { RUST_LOG = \"debug\" }\n",
+        )
+        .unwrap();
+
+        let config: LocalConfig = toml::from_str(
+            r#"include = ["ci/jobs/*.toml"]
+
+[templates.rust-base]
+run_prefix = "rustup target add x86_64-unknown-linux-musl"
+run_suffix = "sccache --show-stats"
+artifacts = ["target/release/brr"]
+env = { RUST_LOG = "info", CARGO_INCREMENTAL = "0" }
+
+[templates.rust-release]
+extends = "rust-base"
+run_prefix = "cargo fetch"
+env = { PROFILE = "release" }
+"#,
+        )
+        .unwrap();
+
+        let resolved = resolve_local_config(tmp.path(), config).unwrap();
+        let job = &resolved.jobs["release"];
+        assert_eq!(
+            job.run,
+            "rustup target add x86_64-unknown-linux-musl\ncargo fetch\ncargo build --release\nsccache --show-stats\n"
+        );
+        assert_eq!(job.artifacts, vec!["target/release/brr", "dist"]);
+        let env = |k: &str| match &job.env[k] {
+            EnvValue::AsString(s) => s.clone(),
+            EnvValue::AsSecret(_) => panic!("{} isn't a string", k),
+        };
+        assert_eq!(env("RUST_LOG"), "debug");
+        assert_eq!(env("CARGO_INCREMENTAL"), "0");
+        assert_eq!(env("PROFILE"), "release");
+    }
+
+    #[test]
+    fn test_reports_template_loops() {
+        let config: LocalConfig = toml::from_str(
+            "[templates.a]\nextends = \"b\"\n\n[templates.b]\nextends = \"a\"\n\n[jobs.build]\nextends = \"a\"\nrun = \"make\"\n\n[jobs.test]\nrun = \"make test\"\n",
+        )
+        .unwrap();
+
+        let err =
+            resolve_job(&config.templates, "build", config.jobs["build"].clone()).unwrap_err();
+        assert!(format!("{:#}", err).contains("a -> b -> a"));
+
+        // The other jobs still run
+        let resolved = resolve_local_config(std::path::Path::new("."), config).unwrap();
+        assert_eq!(resolved.jobs.keys().collect::<Vec<_>>(), vec!["test"]);
+    }
+}
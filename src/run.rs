@@ -1,12 +1,18 @@
 use anyhow::{anyhow, Context, Result};
-use std::{env, path::PathBuf};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
 
 use crate::{
-    api::{ApiClient, BuildRecall, Project, PullQueryParams, PushQueryParams},
+    api::{ApiClient, BuildRecall, Project, PullQueryParams, PushBody, PushQueryParams},
     config_global::read_global_config,
-    config_local::read_local_config,
-    git,
+    config_local::{read_local_config, EnvValue, JobConfig, LocalConfig},
+    git::{self, SyncedTree},
+    interpolate::{interpolate_job, job_vars},
+    progress::PushSummary,
     push::run_push_in_current_dir_retry,
+    submodules,
+    units::TimeSpan,
+    validate::validate_local_config,
 };
 
 pub async fn preattach_to_repo(global_config_dir: PathBuf, slug: String) -> Result<uuid::Uuid> {
@@ -21,8 +27,6 @@ pub async fn preattach_to_repo(global_config_dir: PathBuf, slug: String) -> Resu
         ));
     }
 
-    let path = env::current_dir()?;
-
     let projects = client.list_projects().await?;
     let maybe_project = projects.iter().find(|p| p.slug == slug.clone());
     let project_res: Result<Project> = match maybe_project {
@@ -41,7 +45,8 @@ pub async fn preattach_to_repo(global_config_dir: PathBuf, slug: String) -> Resu
     // create a .git folder for brr to use that doesn't mess with the user's git.
     let g = git::RecallGit::new(global_config_dir.clone())?;
     g.create_shadow_git_folder(slug.clone())
-        .context(format!("Failed to create a shadow git folder (used to sync files without messing with your own git setup) in {:?}/{}", global_config_dir, ".gits"))?;
+        .await
+        .context(format!("Failed to create a shadow git folder (used to sync files without messing with your own git setup) in {:?}", git::shadow_repos_dir(global_config_dir)))?;
 
     Ok(project.id)
 }
@@ -50,6 +55,27 @@ pub async fn preattach_to_repo(global_config_dir: PathBuf, slug: String) -> Resu
 pub struct JobArgs {
     pub job: String,
     pub container: String,
+    // Push even if it's over the size limits in buildrecall.toml
+    pub force: bool,
+    // How long to wait for the job, instead of what its timeout allows
+    pub timeout: Option<TimeSpan>,
+}
+
+pub struct PullOutcome {
+    pub tree_hash: String,
+    // Whether artifacts were ready and got downloaded
+    pub pulled: bool,
+}
+
+/// The result of `brr run`, printed as JSON with `--json`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RunOutput {
+    pub project: String,
+    pub job: String,
+    pub container: String,
+    pub tree_hash: String,
+    /// Set when the tree wasn't built yet and had to be pushed to the farm
+    pub push: Option<PushSummary>,
 }
 
 pub async fn run_pull(
@@ -57,13 +83,13 @@ pub async fn run_pull(
     current_dir: PathBuf,
     slug: String,
     args: JobArgs,
-) -> Result<bool> {
+) -> Result<PullOutcome> {
     let config = read_global_config(global_config_dir.clone())
         .context("Failed to parse the global config ~/.builrecall/config.toml")?;
     let g = git::RecallGit::new(global_config_dir.clone())
         .context("Failed to create a shadow git instance")?;
 
-    let local_config = read_local_config(git::worktree_path(slug.clone())?)?;
+    let local_config = read_local_config(current_dir)?;
     let image = match local_config.containers.get(&args.container) {
         Some(c) => c.image.clone(),
         None => {
@@ -71,15 +97,16 @@ pub async fn run_pull(
         }
     };
 
-    let oid = g
+    let synced = g
         .hash_folder(slug.clone())
         .await
         .context("Failed to hash this folder as a project")?;
 
     let client = ApiClient::new(config);
 
+    let tree_hash = synced.hash.to_string();
     let args = PullQueryParams {
-        tree_hash: oid.to_string(),
+        tree_hash: tree_hash.clone(),
         project_slug: slug,
         job: args.job.clone(),
         container: args.container.clone(),
@@ -91,20 +118,213 @@ pub async fn run_pull(
         .await
         .context("Failed to pull project")?;
 
-    Ok(pulled)
+    Ok(PullOutcome { tree_hash, pulled })
+}
+
+/// What `brr run --dry-run` would have sent, without touching the network
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DryRunOutput {
+    pub project: String,
+    pub tree_hash: String,
+    pub files: Vec<String>,
+    /// The job's script and artifacts, with their variables filled in
+    pub run: String,
+    pub artifacts: Vec<String>,
+    pub push_url: String,
+    pub push: PushQueryParams,
+    /// Sent as the body of the push, not in its URL
+    pub push_body: PushBody,
+    pub pull_url: String,
+    pub pull: PullQueryParams,
+    /// The job's env, with secrets replaced by their name and version
+    pub env: BTreeMap<String, String>,
+}
+
+impl DryRunOutput {
+    pub fn describe(&self) -> String {
+        let mut out = format!("Project: {}\nTree: {}\n", self.project, self.tree_hash);
+
+        out.push_str(&format!("\nFiles ({}):\n", self.files.len()));
+        for f in &self.files {
+            out.push_str(&format!("  {}\n", f));
+        }
+
+        out.push_str("\nRun:\n");
+        for line in self.run.lines() {
+            out.push_str(&format!("  {}\n", line));
+        }
+        out.push_str(&format!("Artifacts: {}\n", self.artifacts.join(", ")));
+
+        out.push_str(&format!("\nPush: {}\n", self.push_url));
+        out.push_str(&format!("Pull: {}\n", self.pull_url));
+
+        out.push_str("\nEnv:\n");
+        for (key, value) in &self.env {
+            out.push_str(&format!("  {}={}\n", key, value));
+        }
+
+        out
+    }
+}
+
+pub async fn dry_run(
+    global_config_dir: PathBuf,
+    current_dir: PathBuf,
+    args: JobArgs,
+) -> Result<DryRunOutput> {
+    let config = read_global_config(global_config_dir.clone())
+        .context("Failed to parse the global config ~/.builrecall/config.toml")?;
+    validate_local_config(current_dir.clone(), Some(&args.job), Some(&args.container))?;
+    let local =
+        read_local_config(current_dir.clone()).context("Failed to read buildrecall.toml")?;
+    let slug = local.project().name.ok_or(anyhow!(
+        "buildrecall.toml is missing a 'project.name' field"
+    ))?;
+
+    let g = git::RecallGit::new(global_config_dir.clone())
+        .context("Failed to create a shadow git instance")?;
+    let synced = g
+        .hash_folder(slug.clone())
+        .await
+        .context("Failed to hash this folder as a project")?;
+    let files = g
+        .tree_files(slug.clone(), synced.tree)
+        .await
+        .context("Failed to list the files in the shadow git repo")?;
+
+    let tree_hash = synced.hash.to_string();
+    let job = job_to_run(&local, &args, &tree_hash)?;
+    let env = job
+        .env
+        .iter()
+        .map(|(key, value)| {
+            let shown = match value {
+                EnvValue::AsString(s) => s.clone(),
+                EnvValue::AsSecret(s) => {
+                    format!("<secret '{}' version {}, redacted>", s.secret, s.version)
+                }
+            };
+            (key.clone(), shown)
+        })
+        .collect();
+
+    let (push, push_body) = push_params(&local, &args, &synced, true)?;
+    let pull = PullQueryParams {
+        tree_hash: tree_hash.clone(),
+        project_slug: slug.clone(),
+        job: args.job,
+        container: args.container,
+        image: push.image.clone(),
+    };
+
+    Ok(DryRunOutput {
+        project: slug,
+        tree_hash,
+        files,
+        run: job.run,
+        artifacts: job.artifacts,
+        push_url: format!("{}/push?{}", config.git_host(), serde_qs::to_string(&push)?),
+        push,
+        push_body,
+        pull_url: format!(
+            "{}/pull?{}",
+            config.scheduler_ws_host(),
+            serde_qs::to_string(&pull)?
+        ),
+        pull,
+        env,
+    })
+}
+
+/// `job` the way it runs on the farm, in `args.container` for `tree_hash`: with its
+/// includes and template resolved and its variables filled in
+pub fn job_to_run(local: &LocalConfig, args: &JobArgs, tree_hash: &str) -> Result<JobConfig> {
+    let job = local.jobs.get(&args.job).ok_or(anyhow!(
+        "There's no job named '{}' in buildrecall.toml",
+        args.job
+    ))?;
+    let vars = job_vars(local, &args.job, &args.container, tree_hash);
+    interpolate_job(&args.job, job, &vars)
+}
+
+/// What a push of `synced` for `args` sends to the farm, in its URL and its body.
+/// `brr run` and its `--dry-run` both go through here, so that they can't disagree.
+pub fn push_params(
+    local: &LocalConfig,
+    args: &JobArgs,
+    synced: &SyncedTree,
+    wait: bool,
+) -> Result<(PushQueryParams, PushBody)> {
+    let tree_hash = synced.hash.to_string();
+    let slug = local.project().name.ok_or(anyhow!(
+        "buildrecall.toml is missing a 'project.name' field"
+    ))?;
+    let image = match local.containers.get(&args.container) {
+        Some(c) => c.image.clone(),
+        None => anyhow::bail!("No image for container named {}", args.container),
+    };
+    let job = job_to_run(local, args, &tree_hash)?;
+
+    let query = PushQueryParams {
+        wait: Some(wait),
+        tree_hash,
+        project_slug: slug,
+        job: args.job.clone(),
+        container: args.container.clone(),
+        image,
+        ..Default::default()
+    }
+    .with_job_limits(&job);
+    let body = PushBody {
+        job_config: Some(job),
+        submodules: Some(submodules::manifest(&synced.submodules)).filter(|m| !m.is_empty()),
+    };
+
+    Ok((query, body))
 }
 
 pub async fn pull_with_push_if_needed(
     global_config_dir: PathBuf,
     current_dir: PathBuf,
     args: JobArgs,
-) -> Result<()> {
+) -> Result<RunOutput> {
+    // Typos are cheaper to catch here than on the farm
+    validate_local_config(current_dir.clone(), Some(&args.job), Some(&args.container))?;
     let local =
         read_local_config(current_dir.clone()).context("Failed to read buildrecall.toml")?;
     let slug = local.project().name.ok_or(anyhow!(
         "buildrecall.toml is missing a 'project.name' field"
     ))?;
 
+    // The farm enforces the job's timeout too, this is for when it never answers
+    let deadline = args
+        .timeout
+        .or_else(|| local.jobs.get(&args.job).and_then(wait_deadline));
+    push_and_pull(global_config_dir, current_dir, slug, args, deadline).await
+}
+
+// Time to queue and pull, on top of running each attempt
+const WAIT_GRACE: Duration = Duration::from_secs(10 * 60);
+
+/// How long `brr run` waits for `job` before giving up. Nothing stops it without
+/// a `timeout`.
+pub fn wait_deadline(job: &JobConfig) -> Option<TimeSpan> {
+    let attempts = 1 + job.retries.unwrap_or(0);
+    job.timeout
+        .and_then(|t| t.0.checked_mul(attempts))
+        .and_then(|t| t.checked_add(WAIT_GRACE))
+        .map(TimeSpan)
+}
+
+// Only the waits on the farm count towards `deadline`, not pushing the tree, which
+// can take long on a first sync or a slow connection
+async fn push_and_pull(
+    global_config_dir: PathBuf,
+    current_dir: PathBuf,
+    slug: String,
+    args: JobArgs,
+    deadline: Option<TimeSpan>,
+) -> Result<RunOutput> {
     preattach_to_repo(global_config_dir.clone(), slug.clone())
         .await
         .context(format!(
@@ -112,29 +332,59 @@ pub async fn pull_with_push_if_needed(
             slug
         ))?;
 
-    let mut pulled = run_pull(
-        global_config_dir.clone(),
-        current_dir.clone(),
-        slug.clone(),
-        args.clone(),
-    )
-    .await?;
-
-    if !pulled {
-        run_push_in_current_dir_retry(global_config_dir.clone(), slug.clone(), args.clone())
-            .await?;
-        pulled = run_pull(
+    let mut outcome = wait_for(
+        &args.job,
+        deadline,
+        run_pull(
             global_config_dir.clone(),
-            current_dir,
+            current_dir.clone(),
             slug.clone(),
             args.clone(),
+        ),
+    )
+    .await?;
+
+    let mut push = None;
+    if !outcome.pulled {
+        push = Some(
+            run_push_in_current_dir_retry(global_config_dir.clone(), slug.clone(), args.clone())
+                .await?,
+        );
+        outcome = wait_for(
+            &args.job,
+            deadline,
+            run_pull(
+                global_config_dir.clone(),
+                current_dir,
+                slug.clone(),
+                args.clone(),
+            ),
         )
         .await?;
     }
 
-    if !pulled {
+    if !outcome.pulled {
         return Err(anyhow!("buildrecall artifacts unavailable for this build"));
     }
 
-    Ok(())
+    Ok(RunOutput {
+        project: slug,
+        job: args.job,
+        container: args.container,
+        tree_hash: outcome.tree_hash,
+        push,
+    })
+}
+
+async fn wait_for<T>(
+    job: &str,
+    deadline: Option<TimeSpan>,
+    wait: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout(deadline.0, wait)
+            .await
+            .map_err(|_| anyhow!("Timed out after {} waiting for the job '{}'", deadline, job))?,
+        None => wait.await,
+    }
 }
@@ -0,0 +1,63 @@
+use anyhow::{Context, Result};
+use clap::Clap;
+use schemars::{gen::SchemaSettings, schema::RootSchema};
+use std::{fs, path::PathBuf};
+
+use crate::config_local::{LocalConfig, CONFIG_FORMAT_VERSION};
+
+/// Prints the JSON Schema of buildrecall.toml, for editors to autocomplete and
+/// check it with. ex: with Even Better TOML in VS Code, save it next to your
+/// buildrecall.toml and put `#:schema ./buildrecall.schema.json` at the top.
+#[derive(Clap, Debug)]
+pub struct Schema {
+    /// Write it to this file instead of stdout
+    #[clap(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+/// The JSON Schema of the current buildrecall.toml format. Like `brr validate`, it
+/// rejects unknown fields, so that editors flag typos as they're made rather than
+/// when `brr run` refuses to start.
+pub fn local_config_schema() -> RootSchema {
+    // TOML has no null, a missing key is how an option is left out
+    let mut schema = SchemaSettings::draft07()
+        .with(|s| s.option_add_null_type = false)
+        .into_generator()
+        .into_root_schema_for::<LocalConfig>();
+    let metadata = schema.schema.metadata();
+    metadata.title = Some("buildrecall.toml".to_string());
+    metadata.description = Some(format!(
+        "Build Recall project config, format version {}",
+        CONFIG_FORMAT_VERSION
+    ));
+    schema
+}
+
+pub async fn run_schema(args: Schema) -> Result<()> {
+    let json = serde_json::to_string_pretty(&local_config_schema())? + "\n";
+    match args.output {
+        Some(path) => {
+            fs::write(&path, json).context(format!("Failed to write to {:?}", path))?;
+            eprintln!("Wrote the schema to {:?}", path);
+        }
+        None => print!("{}", json),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::local_config_schema;
+
+    #[test]
+    fn test_published_schema_is_up_to_date() {
+        // Regenerate it with `brr schema -o buildrecall.schema.json`, and bump
+        // CONFIG_FORMAT_VERSION if buildrecall.toml changed
+        let published: serde_json::Value =
+            serde_json::from_str(include_str!("../buildrecall.schema.json")).unwrap();
+        assert_eq!(
+            serde_json::to_value(local_config_schema()).unwrap(),
+            published
+        );
+    }
+}
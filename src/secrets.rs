@@ -10,9 +10,7 @@ use clap::Clap;
 use crate::{
     api::{ApiClient, BuildRecall},
     config_global::read_global_config,
-    config_local::{
-        overwrite_local_config, read_local_config, EnvValue, JobConfig, LocalConfig, SecretEnv,
-    },
+    config_local::{overwrite_local_config, read_local_config, EnvValue, JobConfig, SecretEnv},
 };
 
 /// Creates a new secret
@@ -77,7 +75,7 @@ pub async fn run_secrets(
             overwrite_local_config(local_config_dir, |f| {
                 let new_secret_env = SecretEnv {
                     secret: new_secret.slug.clone(),
-                    version: new_secret.version.clone(),
+                    version: new_secret.version,
                 };
 
                 let mut jobs: HashMap<String, JobConfig> = HashMap::new();
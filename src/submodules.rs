@@ -0,0 +1,367 @@
+use anyhow::{anyhow, Context, Result};
+use git2::{Index, IndexEntry, IndexTime, Oid, Repository, Status, StatusOptions};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::config_local::{SubmoduleMode, SyncConfig};
+
+const MODE_TREE: i32 = 0o040000;
+const MODE_BLOB: u32 = 0o100644;
+const MODE_EXECUTABLE: u32 = 0o100755;
+const MODE_SYMLINK: u32 = 0o120000;
+const MODE_GITLINK: u32 = 0o160000;
+
+/// Whether `path` (relative to `worktree`) is the root of a submodule or nested repo
+pub fn is_nested_repo(worktree: &Path, path: &Path) -> bool {
+    !path.as_os_str().is_empty() && worktree.join(path).join(".git").exists()
+}
+
+/// The commit the repo in `dir` has checked out. One without any commits gets a
+/// zeroed out ID, it can still be materialized.
+pub fn checked_out_commit(dir: &Path) -> Oid {
+    Repository::open(dir)
+        .ok()
+        .and_then(|r| r.head().ok()?.target())
+        .unwrap_or_else(Oid::zero)
+}
+
+/// Stages each nested repo as a gitlink to its `checked_out_commit`
+pub fn stage_gitlinks(index: &mut Index, worktree: &Path, nested: &[PathBuf]) -> Result<()> {
+    for path in nested {
+        let dir = worktree.join(path);
+        let commit = checked_out_commit(&dir);
+
+        let path = path
+            .to_str()
+            .ok_or(anyhow!("Failed to convert {:?} to string", path))?
+            .trim_end_matches('/')
+            .replace('\\', "/");
+        let already_staged = index
+            .get_path(Path::new(&path), 0)
+            .map(|e| e.mode == MODE_GITLINK && e.id == commit)
+            .unwrap_or(false);
+        if already_staged {
+            continue;
+        }
+
+        // A folder that just became a repo still has its files in the index
+        index
+            .remove_dir(Path::new(&path), 0)
+            .context(format!("Failed to unstage the files in {:?}", dir))?;
+        index
+            .add(&index_entry(path, MODE_GITLINK, commit))
+            .context(format!("Failed to stage the nested git repo in {:?}", dir))?;
+    }
+
+    Ok(())
+}
+
+/// Whether the index holds any submodules or nested repos, stored as gitlinks
+pub fn has_gitlinks(index: &Index) -> bool {
+    index.iter().any(|e| e.mode == MODE_GITLINK)
+}
+
+/// Replaces the gitlinks in `tree_oid` with the files of the repos they point to,
+/// as they are on disk in `worktree`, following the `[sync.submodules]` settings.
+/// Returns the new tree, or `tree_oid` when there was nothing to replace, along
+/// with the commit each replaced gitlink pointed to, by path.
+pub fn materialize(
+    repo: &Repository,
+    tree_oid: Oid,
+    worktree: &Path,
+    sync: &SyncConfig,
+) -> Result<(Oid, BTreeMap<String, Oid>)> {
+    let mut commits = BTreeMap::new();
+    let tree_oid = materialize_tree(repo, tree_oid, worktree, "", sync, &mut commits)?;
+    Ok((tree_oid, commits))
+}
+
+/// One "<commit> <path>" line per materialized submodule, sorted by path. Sent to
+/// the farm with each push rather than written into the tree, so that builds
+/// don't find a file that isn't in the project.
+pub fn manifest(commits: &BTreeMap<String, Oid>) -> String {
+    commits
+        .iter()
+        .map(|(path, commit)| format!("{} {}\n", commit, path))
+        .collect()
+}
+
+fn materialize_tree(
+    repo: &Repository,
+    tree_oid: Oid,
+    dir: &Path,
+    prefix: &str,
+    sync: &SyncConfig,
+    commits: &mut BTreeMap<String, Oid>,
+) -> Result<Oid> {
+    let tree = repo.find_tree(tree_oid)?;
+    let mut builder = repo.treebuilder(Some(&tree))?;
+    let mut changed = false;
+
+    for entry in tree.iter() {
+        let name = entry.name().ok_or(anyhow!(
+            "{}{:?} is not valid utf-8",
+            prefix,
+            entry.name_bytes()
+        ))?;
+        let path = format!("{}{}", prefix, name);
+
+        if entry.filemode() == MODE_TREE {
+            let sub_prefix = format!("{}/", path);
+            let new_oid = materialize_tree(
+                repo,
+                entry.id(),
+                &dir.join(name),
+                &sub_prefix,
+                sync,
+                commits,
+            )?;
+            if new_oid != entry.id() {
+                builder.insert(name, new_oid, MODE_TREE)?;
+                changed = true;
+            }
+        } else if entry.filemode() as u32 == MODE_GITLINK {
+            match sync.submodule_mode(&path) {
+                SubmoduleMode::Materialize => {
+                    let new_oid = tree_from_nested_repo(repo, &dir.join(name), &path, sync, commits)
+                        .context(format!(
+                            "Failed to sync the submodule or nested repo at {:?}. Set it to \"gitlink\" or \"skip\" in the [sync.submodules] section of buildrecall.toml to leave it out.",
+                            path
+                        ))?;
+                    builder.insert(name, new_oid, MODE_TREE)?;
+                    commits.insert(path, entry.id());
+                    changed = true;
+                }
+                SubmoduleMode::Skip => {
+                    builder.remove(name)?;
+                    changed = true;
+                }
+                SubmoduleMode::Gitlink => {}
+            }
+        }
+    }
+
+    if changed {
+        Ok(builder.write()?)
+    } else {
+        Ok(tree_oid)
+    }
+}
+
+// The nested repo's tracked files plus its untracked, non-ignored ones, ie. what
+// a build in that folder would see. Its own nested repos are handled recursively.
+fn tree_from_nested_repo(
+    repo: &Repository,
+    dir: &Path,
+    path: &str,
+    sync: &SyncConfig,
+    commits: &mut BTreeMap<String, Oid>,
+) -> Result<Oid> {
+    let nested =
+        Repository::open(dir).context(format!("Failed to open the git repo in {:?}", dir))?;
+
+    // Path to its mode in the nested repo's index, if it's tracked
+    let mut files: BTreeMap<String, Option<(u32, Oid)>> = BTreeMap::new();
+    for e in nested.index()?.iter() {
+        files.insert(
+            String::from_utf8_lossy(&e.path).to_string(),
+            Some((e.mode, e.id)),
+        );
+    }
+
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .exclude_submodules(true);
+    for s in nested.statuses(Some(&mut opts))?.iter() {
+        if s.status().contains(Status::WT_NEW) {
+            if let Some(p) = s.path() {
+                files.entry(p.to_string()).or_insert(None);
+            }
+        }
+    }
+
+    let mut index = Index::new()?;
+    for (file, tracked) in files {
+        let abs = dir.join(&file);
+        let (mode, id) = match tracked {
+            Some((MODE_GITLINK, commit)) => (MODE_GITLINK, commit),
+            _ => match fs::symlink_metadata(&abs) {
+                Ok(meta) if meta.file_type().is_symlink() => {
+                    let target = fs::read_link(&abs)
+                        .context(format!("Failed to read the symlink {:?}", abs))?;
+                    let target = target
+                        .to_str()
+                        .ok_or(anyhow!("Failed to convert {:?} to string", target))?;
+                    (MODE_SYMLINK, repo.blob(target.as_bytes())?)
+                }
+                Ok(meta) if meta.is_file() => (
+                    file_mode(&meta, tracked.map(|t| t.0)),
+                    repo.blob_path(&abs)
+                        .context(format!("Failed to read {:?}", abs))?,
+                ),
+                // Deleted, but not staged as such
+                _ => continue,
+            },
+        };
+
+        index.add(&index_entry(file, mode, id))?;
+    }
+
+    let tree_oid = index.write_tree_to(repo)?;
+    materialize_tree(repo, tree_oid, dir, &format!("{}/", path), sync, commits)
+}
+
+// Only the mode, ID and path matter to libgit2 when writing a tree
+fn index_entry(path: String, mode: u32, id: Oid) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id,
+        flags: path.len().min(0xfff) as u16,
+        flags_extended: 0,
+        path: path.into_bytes(),
+    }
+}
+
+#[cfg(unix)]
+fn file_mode(meta: &fs::Metadata, _tracked: Option<u32>) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    if meta.permissions().mode() & 0o111 != 0 {
+        MODE_EXECUTABLE
+    } else {
+        MODE_BLOB
+    }
+}
+
+#[cfg(not(unix))]
+fn file_mode(_meta: &fs::Metadata, tracked: Option<u32>) -> u32 {
+    // The filesystem can't tell us, so trust what the nested repo recorded
+    match tracked {
+        Some(MODE_EXECUTABLE) => MODE_EXECUTABLE,
+        _ => MODE_BLOB,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use git2::{IndexAddOption, Oid, Repository};
+    use std::{collections::HashMap, fs, path::Path};
+    use tempdir::TempDir;
+
+    use crate::{
+        config_local::{SubmoduleMode, SyncConfig},
+        git::{write_worktree_tree, SyncedTree},
+    };
+
+    fn commit_all(repo: &Repository) -> Oid {
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"].iter(), IndexAddOption::DEFAULT, None)
+            .unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test").unwrap();
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let parents = parent.iter().collect::<Vec<_>>();
+        repo.commit(Some("HEAD"), &sig, &sig, "test", &tree, &parents)
+            .unwrap()
+    }
+
+    fn sync(worktree: &Path, shadow: &Repository, config: &SyncConfig) -> SyncedTree {
+        shadow.set_workdir(worktree, false).unwrap();
+        let synced = write_worktree_tree(shadow, config).unwrap();
+        // Again, to check that what the last sync left in the index is fine
+        assert_eq!(write_worktree_tree(shadow, config).unwrap(), synced);
+        synced
+    }
+
+    #[test]
+    fn test_materializes_nested_repos() {
+        let tmp = TempDir::new(".submodules")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let worktree = tmp.path().join("project");
+        let vendored = worktree.join("vendor").join("dep");
+        fs::create_dir_all(&vendored).unwrap();
+        fs::write(worktree.join("main.rs"), "fn main() {}").unwrap();
+        fs::write(vendored.join("lib.rs"), "pub fn dep() {}").unwrap();
+
+        let nested = Repository::init(&vendored).unwrap();
+        let commit = commit_all(&nested);
+        fs::write(vendored.join("untracked.rs"), "// new").unwrap();
+
+        let shadow = Repository::init_bare(tmp.path().join("shadow")).unwrap();
+        let synced = sync(&worktree, &shadow, &SyncConfig::default());
+        let tree = shadow.find_tree(synced.tree).unwrap();
+
+        for path in &["vendor/dep/lib.rs", "vendor/dep/untracked.rs", "main.rs"] {
+            let entry = tree.get_path(Path::new(path)).unwrap();
+            assert_eq!(entry.kind(), Some(git2::ObjectType::Blob), "{}", path);
+        }
+        assert!(tree.get_path(Path::new("vendor/dep/.git")).is_err());
+        // The commits are sent next to the tree, not in it
+        let mut names: Vec<_> = tree.iter().map(|e| e.name().unwrap().to_string()).collect();
+        names.sort();
+        assert_eq!(names, vec!["main.rs", "vendor"]);
+        assert_eq!(
+            synced.submodules.into_iter().collect::<Vec<_>>(),
+            vec![("vendor/dep".to_string(), commit)]
+        );
+        assert_ne!(synced.hash, synced.tree);
+
+        // Moving the submodule to another commit changes the hash, even with the same files
+        fs::remove_file(vendored.join("untracked.rs")).unwrap();
+        commit_all(&nested);
+        fs::write(vendored.join("untracked.rs"), "// new").unwrap();
+        let moved = sync(&worktree, &shadow, &SyncConfig::default());
+        assert_eq!(moved.tree, synced.tree);
+        assert_ne!(moved.hash, synced.hash);
+    }
+
+    #[test]
+    fn test_follows_the_per_path_setting() {
+        let tmp = TempDir::new(".submodules")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let worktree = tmp.path().join("project");
+        for name in &["kept", "skipped"] {
+            let dir = worktree.join(name);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("file"), name).unwrap();
+            commit_all(&Repository::init(&dir).unwrap());
+        }
+
+        let mut submodules = HashMap::new();
+        submodules.insert("kept".to_string(), SubmoduleMode::Gitlink);
+        submodules.insert("skipped".to_string(), SubmoduleMode::Skip);
+        let config = SyncConfig {
+            submodules,
+            ..SyncConfig::default()
+        };
+
+        let shadow = Repository::init_bare(tmp.path().join("shadow")).unwrap();
+        let synced = sync(&worktree, &shadow, &config);
+        let tree = shadow.find_tree(synced.tree).unwrap();
+
+        assert_eq!(
+            tree.get_path(Path::new("kept")).unwrap().filemode(),
+            0o160000
+        );
+        assert!(tree.get_path(Path::new("skipped")).is_err());
+        // Gitlinks already record their commit in the tree
+        assert!(synced.submodules.is_empty());
+        assert_eq!(synced.hash, synced.tree);
+    }
+}
@@ -0,0 +1,289 @@
+use anyhow::{anyhow, Result};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr, time::Duration};
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const GIB: u64 = 1024 * MIB;
+
+/// Formats a byte count for humans, ex: "3.2 MiB"
+pub fn format_bytes(bytes: u64) -> String {
+    if bytes >= GIB {
+        format!("{:.1} GiB", bytes as f64 / GIB as f64)
+    } else if bytes >= MIB {
+        format!("{:.1} MiB", bytes as f64 / MIB as f64)
+    } else if bytes >= KIB {
+        format!("{:.1} KiB", bytes as f64 / KIB as f64)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+/// A size in bytes, as written in buildrecall.toml. Either a plain number of bytes
+/// or a string with a unit, ex: `"100MB"`. Units are powers of 1024, so "MB" and
+/// "MiB" are the same thing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let trimmed = s.trim();
+        let split = trimmed
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(trimmed.len());
+        let (number, unit) = trimmed.split_at(split);
+
+        let number: u64 = number
+            .parse()
+            .map_err(|_| anyhow!("'{}' is not a size, expected something like '100MB'", s))?;
+        let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" | "kib" => KIB,
+            "m" | "mb" | "mib" => MIB,
+            "g" | "gb" | "gib" => GIB,
+            _ => {
+                return Err(anyhow!(
+                    "'{}' has an unknown unit '{}', expected one of B, KB, MB or GB",
+                    s,
+                    unit.trim()
+                ))
+            }
+        };
+
+        number
+            .checked_mul(multiplier)
+            .map(ByteSize)
+            .ok_or(anyhow!("'{}' is too big", s))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.0;
+        let divides = |unit: u64| bytes != 0 && bytes.checked_rem(unit) == Some(0);
+        if divides(GIB) {
+            write!(f, "{}GB", bytes / GIB)
+        } else if divides(MIB) {
+            write!(f, "{}MB", bytes / MIB)
+        } else if divides(KIB) {
+            write!(f, "{}KB", bytes / KIB)
+        } else {
+            write!(f, "{}B", bytes)
+        }
+    }
+}
+
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            WithUnit(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(b) => Ok(ByteSize(b)),
+            Raw::WithUnit(s) => s.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+impl JsonSchema for ByteSize {
+    fn schema_name() -> String {
+        "ByteSize".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        serde_json::from_value(serde_json::json!({
+            "description": "A number of bytes, or a size with a unit, ex: \"100MB\"",
+            "anyOf": [
+                { "type": "integer", "minimum": 0 },
+                { "type": "string", "pattern": "^\\s*[0-9]+\\s*([bB]|[kKmMgG]([iI]?[bB])?)?\\s*$" }
+            ]
+        }))
+        .expect("The ByteSize schema is valid")
+    }
+}
+
+/// A length of time, as written in buildrecall.toml or given on the command line.
+/// Either a plain number of seconds or a string of numbers with units, ex: `"90s"`,
+/// `"45m"` or `"1h30m"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeSpan(pub Duration);
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+
+impl FromStr for TimeSpan {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let trimmed = s.trim();
+        if let Ok(secs) = trimmed.parse::<u64>() {
+            return Ok(TimeSpan(Duration::from_secs(secs)));
+        }
+
+        if trimmed.is_empty() {
+            return Err(anyhow!(
+                "The duration is empty, expected something like '45m'"
+            ));
+        }
+
+        let mut secs: u64 = 0;
+        let mut rest = trimmed;
+        while !rest.is_empty() {
+            let split = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let number: u64 = rest[..split]
+                .parse()
+                .map_err(|_| anyhow!("'{}' is not a duration, expected something like '45m'", s))?;
+            let unit_len = rest[split..]
+                .find(|c: char| c.is_ascii_digit())
+                .unwrap_or(rest.len() - split);
+            let unit = rest[split..split + unit_len].trim();
+            let multiplier = match unit.to_ascii_lowercase().as_str() {
+                "s" | "sec" | "secs" => 1,
+                "m" | "min" | "mins" => MINUTE,
+                "h" | "hr" | "hrs" => HOUR,
+                _ => {
+                    return Err(anyhow!(
+                        "'{}' has an unknown unit '{}', expected one of s, m or h",
+                        s,
+                        unit
+                    ))
+                }
+            };
+            secs = number
+                .checked_mul(multiplier)
+                .and_then(|n| n.checked_add(secs))
+                .ok_or(anyhow!("'{}' is too long", s))?;
+            rest = rest[split + unit_len..].trim_start();
+        }
+
+        Ok(TimeSpan(Duration::from_secs(secs)))
+    }
+}
+
+impl fmt::Display for TimeSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0.as_secs();
+        let (hours, minutes, seconds) = (secs / HOUR, secs % HOUR / MINUTE, secs % MINUTE);
+        if secs == 0 {
+            return write!(f, "0s");
+        }
+        if hours > 0 {
+            write!(f, "{}h", hours)?;
+        }
+        if minutes > 0 {
+            write!(f, "{}m", minutes)?;
+        }
+        if seconds > 0 {
+            write!(f, "{}s", seconds)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for TimeSpan {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeSpan {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Seconds(u64),
+            WithUnit(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Seconds(s) => Ok(TimeSpan(Duration::from_secs(s))),
+            Raw::WithUnit(s) => s.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+impl JsonSchema for TimeSpan {
+    fn schema_name() -> String {
+        "TimeSpan".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        serde_json::from_value(serde_json::json!({
+            "description": "A number of seconds, or a duration with units, ex: \"45m\" or \"1h30m\"",
+            "anyOf": [
+                { "type": "integer", "minimum": 0 },
+                { "type": "string", "pattern": "^\\s*([0-9]+\\s*([sS]|[mM]|[hH])[a-zA-Z]*\\s*)+$" }
+            ]
+        }))
+        .expect("The TimeSpan schema is valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{format_bytes, ByteSize, TimeSpan};
+
+    #[test]
+    fn test_parses_sizes() {
+        assert_eq!("512".parse::<ByteSize>().unwrap(), ByteSize(512));
+        assert_eq!("10KB".parse::<ByteSize>().unwrap(), ByteSize(10 * 1024));
+        assert_eq!(
+            "100 MiB".parse::<ByteSize>().unwrap(),
+            ByteSize(100 * 1024 * 1024)
+        );
+        assert_eq!(
+            "2g".parse::<ByteSize>().unwrap(),
+            ByteSize(2 * 1024 * 1024 * 1024)
+        );
+        assert!("lots".parse::<ByteSize>().is_err());
+        assert!("10 parsecs".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn test_round_trips_through_display() {
+        for s in &["100MB", "3GB", "7KB", "1000B"] {
+            assert_eq!(s.parse::<ByteSize>().unwrap().to_string(), *s);
+        }
+    }
+
+    #[test]
+    fn test_formats_bytes() {
+        assert_eq!(format_bytes(12), "12 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_parses_durations() {
+        let secs = |s: &str| s.parse::<TimeSpan>().unwrap().0;
+        assert_eq!(secs("90"), Duration::from_secs(90));
+        assert_eq!(secs("30s"), Duration::from_secs(30));
+        assert_eq!(secs("45m"), Duration::from_secs(45 * 60));
+        assert_eq!(secs("1h30m"), Duration::from_secs(90 * 60));
+        assert_eq!(secs("2 hrs 5 mins"), Duration::from_secs(2 * 3600 + 5 * 60));
+        assert!("soon".parse::<TimeSpan>().is_err());
+        assert!("".parse::<TimeSpan>().is_err());
+        assert!("3 days".parse::<TimeSpan>().is_err());
+
+        for s in &["1h30m", "45m", "10s", "0s"] {
+            assert_eq!(s.parse::<TimeSpan>().unwrap().to_string(), *s);
+        }
+    }
+}
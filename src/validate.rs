@@ -0,0 +1,603 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Clap;
+use serde::Deserialize;
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config_local::{LocalConfig, LOCAL_CONFIG_NAME},
+    interpolate::{interpolate_job, job_vars, unresolved_job_vars, BUILTIN_VARS},
+    migrate::migrate_local_config,
+    resolve::{merge_includes, resolve_job},
+};
+
+/// Checks buildrecall.toml for mistakes: unknown fields, values of the wrong type,
+/// empty run scripts and malformed secrets. `brr run` does this too, before it
+/// sends anything to the farm.
+#[derive(Clap, Debug)]
+pub struct Validate {
+    /// Also check that this job exists
+    pub job: Option<String>,
+
+    /// Also check that this container exists
+    pub container: Option<String>,
+}
+
+/// Something wrong with buildrecall.toml
+#[derive(Clone, Debug, PartialEq)]
+pub struct Problem {
+    /// Line and column, both starting at 1
+    pub location: Option<(usize, usize)>,
+    /// The job it's in, when it only affects that job
+    pub job: Option<String>,
+    /// Worth knowing about, but nothing fails over it
+    pub warning: bool,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.location {
+            Some((line, col)) => write!(
+                f,
+                "{}:{}:{}: {}",
+                LOCAL_CONFIG_NAME, line, col, self.message
+            ),
+            None => write!(f, "{}: {}", LOCAL_CONFIG_NAME, self.message),
+        }
+    }
+}
+
+pub async fn run_validate(local_config_dir: PathBuf, args: Validate) -> Result<()> {
+    validate_local_config(
+        local_config_dir,
+        args.job.as_deref(),
+        args.container.as_deref(),
+    )?;
+    eprintln!("{} looks good", LOCAL_CONFIG_NAME);
+    Ok(())
+}
+
+/// Fails with every problem in the buildrecall.toml in `dir`. Warnings, and when
+/// `job` is given, problems that only affect other jobs, are only printed.
+pub fn validate_local_config(
+    dir: PathBuf,
+    job: Option<&str>,
+    container: Option<&str>,
+) -> Result<()> {
+    let filepath = dir.join(LOCAL_CONFIG_NAME);
+    let text = fs::read_to_string(&filepath).context(format!(
+        "Can't read {:?}, run `brr init` to create it",
+        filepath
+    ))?;
+
+    let (problems, elsewhere): (Vec<_>, Vec<_>) = check_local_config(&dir, &text, job, container)
+        .into_iter()
+        .partition(|p| match (&p.job, job) {
+            _ if p.warning => false,
+            (Some(theirs), Some(ours)) => theirs == ours,
+            _ => true,
+        });
+    for p in elsewhere {
+        eprintln!("Warning: {}", p);
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let listed = problems
+        .iter()
+        .map(|p| format!("  {}\n", p))
+        .collect::<String>();
+    Err(anyhow!(
+        "Found {} problem{} in {:?}:\n{}",
+        problems.len(),
+        if problems.len() == 1 { "" } else { "s" },
+        filepath,
+        listed
+    ))
+}
+
+/// Every problem in the buildrecall.toml `text`, whose includes are relative to
+/// `dir`. `job` and `container` are the ones about to be run, if any.
+pub fn check_local_config(
+    dir: &Path,
+    text: &str,
+    job: Option<&str>,
+    container: Option<&str>,
+) -> Vec<Problem> {
+    let mut problems = vec![];
+
+    let mut written: toml::value::Table = match toml::from_str(text) {
+        Ok(t) => t,
+        Err(e) => return vec![parse_problem(&e)],
+    };
+    let migrated = match migrate_local_config(&mut written) {
+        Ok(applied) => !applied.is_empty(),
+        Err(e) => {
+            return vec![Problem {
+                location: locate(text, &["version"]),
+                job: None,
+                warning: false,
+                message: format!("{:#}", e),
+            }]
+        }
+    };
+
+    let mut unknown = vec![];
+    let mut ignored = |path: serde_ignored::Path| unknown.push(path.to_string());
+    // Older formats are checked once upgraded, where their locations are a best
+    // guess. The current one is checked as written, for exact ones.
+    let parsed = if migrated {
+        serde_ignored::deserialize(toml::Value::Table(written), &mut ignored)
+    } else {
+        serde_ignored::deserialize(&mut toml::Deserializer::new(text), &mut ignored)
+    };
+    let config: LocalConfig = match parsed {
+        Ok(c) => c,
+        Err(e) => {
+            // A broken secret fails as not matching any kind of env value, which
+            // doesn't say much
+            let secrets = check_secrets(text);
+            if secrets.is_empty() {
+                return vec![parse_problem(&e)];
+            }
+            return secrets;
+        }
+    };
+    for path in unknown {
+        let keys: Vec<&str> = path.split('.').collect();
+        problems.push(Problem {
+            location: locate(text, &keys),
+            job: job_of(&keys),
+            warning: false,
+            message: format!("unknown field `{}`", path),
+        });
+    }
+
+    problems.extend(check_secrets(text));
+
+    // Included jobs and templates are only known once they're merged in
+    let mut config = match merge_includes(dir, config) {
+        Ok(c) => c,
+        Err(e) => {
+            problems.push(Problem {
+                location: None,
+                job: None,
+                warning: false,
+                message: format!("{:#}", e),
+            });
+            return problems;
+        }
+    };
+
+    // A job whose template can't be applied is left out of the checks below, the
+    // others are still checked
+    let job_names: Vec<String> = config.jobs.keys().cloned().collect();
+    let mut jobs: Vec<_> = config.jobs.drain().collect();
+    jobs.sort_by(|a, b| a.0.cmp(&b.0));
+    for (name, job) in jobs {
+        match resolve_job(&config.templates, &name, job) {
+            Ok(job) => {
+                config.jobs.insert(name, job);
+            }
+            Err(e) => problems.push(Problem {
+                location: locate(text, &["jobs", &name, "extends"]),
+                job: Some(name),
+                warning: false,
+                message: format!("{:#}", e),
+            }),
+        }
+    }
+
+    let mut vars: Vec<_> = config
+        .vars
+        .keys()
+        .filter(|v| BUILTIN_VARS.contains(&v.as_str()))
+        .collect();
+    vars.sort();
+    for var in vars {
+        problems.push(Problem {
+            location: locate(text, &["vars", var]),
+            job: None,
+            warning: false,
+            message: format!("`{}` is set by brr and can't be in [vars]", var),
+        });
+    }
+
+    let mut jobs: Vec<_> = config.jobs.iter().collect();
+    jobs.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, job) in jobs {
+        if job.run.trim().is_empty() {
+            problems.push(Problem {
+                location: locate(text, &["jobs", name, "run"]),
+                job: Some(name.clone()),
+                warning: false,
+                message: format!("the job `{}` has an empty `run` script", name),
+            });
+        }
+        if matches!(job.timeout, Some(t) if t.0.as_secs() == 0) {
+            problems.push(Problem {
+                location: locate(text, &["jobs", name, "timeout"]),
+                job: Some(name.clone()),
+                warning: false,
+                message: format!("the job `{}` has a `timeout` of 0", name),
+            });
+        }
+        if !job.retry_on.is_empty() && job.retries.unwrap_or(0) == 0 {
+            problems.push(Problem {
+                location: locate(text, &["jobs", name, "retry_on"]),
+                job: Some(name.clone()),
+                warning: false,
+                message: format!(
+                    "the job `{}` has a `retry_on` but no `retries`, so it's never retried",
+                    name
+                ),
+            });
+        }
+        // The built in variables are only known when it runs
+        let vars = job_vars(&config, name, container.unwrap_or_default(), "");
+        if let Err(e) = interpolate_job(name, job, &vars) {
+            problems.push(Problem {
+                location: locate(text, &["jobs", name]),
+                job: Some(name.clone()),
+                warning: false,
+                message: format!("{:#}", e),
+            });
+        }
+        for var in unresolved_job_vars(job, &vars) {
+            problems.push(Problem {
+                location: locate(text, &["jobs", name]),
+                job: Some(name.clone()),
+                warning: true,
+                message: format!(
+                    "`${{{}}}` in the job `{}` isn't in [vars] or built in, so it's left for the shell",
+                    var, name
+                ),
+            });
+        }
+    }
+
+    if let Some(job) = job {
+        if !job_names.iter().any(|n| n == job) {
+            problems.push(Problem {
+                location: None,
+                job: None,
+                warning: false,
+                message: format!(
+                    "there's no job named `{}`{}",
+                    job,
+                    expected_one_of(job_names.iter())
+                ),
+            });
+        }
+    }
+    if let Some(container) = container {
+        if !config.containers.contains_key(container) {
+            problems.push(Problem {
+                location: None,
+                job: None,
+                warning: false,
+                message: format!(
+                    "there's no container named `{}`, add a [containers.{}] with its image{}",
+                    container,
+                    container,
+                    expected_one_of(config.containers.keys())
+                ),
+            });
+        }
+    }
+
+    problems
+}
+
+// Secrets are an untagged variant of env values, so serde can only say that they
+// don't match. These say what's wrong with them instead.
+fn check_secrets(text: &str) -> Vec<Problem> {
+    #[derive(Deserialize)]
+    struct Raw {
+        #[serde(default)]
+        templates: toml::value::Table,
+        #[serde(default)]
+        jobs: toml::value::Table,
+    }
+
+    let raw: Raw = match toml::from_str(text) {
+        Ok(r) => r,
+        Err(_) => return vec![],
+    };
+
+    let mut problems = vec![];
+    let sections = raw
+        .templates
+        .into_iter()
+        .map(|t| ("templates", t))
+        .chain(raw.jobs.into_iter().map(|j| ("jobs", j)));
+    for (section, (job_name, job)) in sections {
+        let env = match job.get("env").and_then(|e| e.as_table()) {
+            Some(e) => e.clone(),
+            None => continue,
+        };
+        for (key, value) in env {
+            let secret = match value.as_table() {
+                Some(s) => s,
+                None => continue,
+            };
+            let location = locate(text, &[section, &job_name, "env", &key]);
+            let job = job_of(&[section, &job_name]);
+            let mut problem = |message: String| {
+                problems.push(Problem {
+                    location,
+                    job: job.clone(),
+                    warning: false,
+                    message: format!(
+                        "the secret for `{}.{}.env.{}` {}",
+                        section, job_name, key, message
+                    ),
+                })
+            };
+
+            match secret.get("secret").map(|s| s.as_str()) {
+                None => problem("is missing its `secret` name".to_string()),
+                Some(None) | Some(Some("")) => problem(
+                    "should have a `secret` name, as created with `brr secrets set`".to_string(),
+                ),
+                Some(Some(_)) => {}
+            }
+            match secret.get("version").map(|v| v.as_integer()) {
+                None => problem("is missing its `version`".to_string()),
+                Some(Some(v)) if v >= 1 => {}
+                Some(_) => problem("should have a `version` of 1 or more".to_string()),
+            }
+            for field in secret.keys().filter(|k| *k != "secret" && *k != "version") {
+                problem(format!(
+                    "has an unknown field `{}`, expected `secret` and `version`",
+                    field
+                ));
+            }
+        }
+    }
+    problems
+}
+
+fn parse_problem(e: &toml::de::Error) -> Problem {
+    let message = e.to_string();
+    // The location is already in `location`
+    let message = match message.find(" at line ") {
+        Some(i) => message[..i].to_string(),
+        None => message,
+    };
+    Problem {
+        location: e.line_col().map(|(line, col)| (line + 1, col + 1)),
+        job: None,
+        warning: false,
+        message,
+    }
+}
+
+// The job that the dotted key `keys` is in, if any
+fn job_of(keys: &[&str]) -> Option<String> {
+    match keys {
+        ["jobs", name, ..] => Some(name.to_string()),
+        _ => None,
+    }
+}
+
+fn expected_one_of<'a>(names: impl Iterator<Item = &'a String>) -> String {
+    let mut names: Vec<_> = names.map(|n| format!("`{}`", n)).collect();
+    names.sort();
+    if names.is_empty() {
+        String::new()
+    } else {
+        format!(", expected one of {}", names.join(", "))
+    }
+}
+
+/// Where the dotted key `keys` is written in `text`, as a line and column starting
+/// at 1. Falls back to the closest parent that can be found.
+pub fn locate(text: &str, keys: &[&str]) -> Option<(usize, usize)> {
+    (1..=keys.len())
+        .rev()
+        .find_map(|len| locate_exactly(text, &keys[..len]))
+}
+
+fn locate_exactly(text: &str, keys: &[&str]) -> Option<(usize, usize)> {
+    let mut table: Vec<String> = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let indent = line.len() - trimmed.len();
+
+        if trimmed.starts_with('[') {
+            let header = trimmed.trim_start_matches('[');
+            let header = header.split(']').next().unwrap_or_default();
+            table = split_dotted(header);
+            if table == keys {
+                return Some((i + 1, indent + 1));
+            }
+            continue;
+        }
+
+        let (key, rest) = match trimmed.split_once('=') {
+            Some(kv) if !trimmed.starts_with('#') => kv,
+            _ => continue,
+        };
+        let mut full = table.clone();
+        full.extend(split_dotted(key));
+        if full == keys {
+            return Some((i + 1, indent + 1));
+        }
+
+        // Inside an inline table, ex: env = { TOKEN = { secret = "..", version = 1 } }
+        if keys.len() > full.len() && keys.iter().zip(&full).all(|(k, f)| k == f) {
+            let mut offset = line.len() - rest.len();
+            for key in &keys[full.len()..] {
+                offset += line[offset..].find(key)?;
+            }
+            return Some((i + 1, offset + 1));
+        }
+    }
+    None
+}
+
+fn split_dotted(key: &str) -> Vec<String> {
+    key.split('.')
+        .map(|k| k.trim().trim_matches('"').trim_matches('\'').to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use std::{fs, path::Path};
+    use tempdir::TempDir;
+
+    use super::{check_local_config, validate_local_config, Problem};
+
+    const CONFIG: &str = r#"[project]
+name = "app"
+
+[jobs.build]
+run = "cargo build"
+artifact = ["target"]
+
+[jobs.lint]
+run = "  "
+
+[containers.default]
+image = "rust:1.55"
+"#;
+
+    #[test]
+    fn test_reports_every_problem_with_its_location() {
+        let problems = check_local_config(Path::new("."), CONFIG, Some("test"), Some("default"));
+        assert_eq!(
+            problems,
+            vec![
+                Problem {
+                    location: Some((6, 1)),
+                    job: Some("build".to_string()),
+                    warning: false,
+                    message: "unknown field `jobs.build.artifact`".to_string(),
+                },
+                Problem {
+                    location: Some((9, 1)),
+                    job: Some("lint".to_string()),
+                    warning: false,
+                    message: "the job `lint` has an empty `run` script".to_string(),
+                },
+                Problem {
+                    location: None,
+                    job: None,
+                    warning: false,
+                    message: "there's no job named `test`, expected one of `build`, `lint`"
+                        .to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_explains_broken_secrets() {
+        let problems = check_local_config(
+            Path::new("."),
+            "[jobs.build]\nrun = \"make\"\nenv = { TOKEN = { secret = \"token\" } }\n",
+            None,
+            None,
+        );
+        assert_eq!(
+            problems,
+            vec![Problem {
+                location: Some((3, 9)),
+                job: Some("build".to_string()),
+                warning: false,
+                message: "the secret for `jobs.build.env.TOKEN` is missing its `version`"
+                    .to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_warns_about_undefined_vars() {
+        let tmp = TempDir::new(".validate")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        let text = "[vars]\ntarget = \"x86_64-apple-darwin\"\n\n[jobs.build]\nrun = \"cargo build --target=${target} # ${tree_hash}\"\nartifacts = [\"target/${triple}/release/brr\"]\n\n[containers.default]\nimage = \"rust:1.55\"\n";
+        let problems = check_local_config(tmp.path(), text, None, None);
+        assert_eq!(
+            problems,
+            vec![Problem {
+                location: Some((4, 1)),
+                job: Some("build".to_string()),
+                warning: true,
+                message: "`${triple}` in the job `build` isn't in [vars] or built in, so it's left for the shell"
+                    .to_string(),
+            }]
+        );
+
+        // Scripts that use shell variables in braces still run
+        fs::write(tmp.path().join("buildrecall.toml"), text).unwrap();
+        assert!(
+            validate_local_config(tmp.path().to_path_buf(), Some("build"), Some("default")).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_reports_retries_that_never_happen() {
+        let problems = check_local_config(
+            Path::new("."),
+            "[jobs.build]\nrun = \"make\"\ntimeout = \"45m\"\nretry_on = [\"infra\", 137]\n",
+            None,
+            None,
+        );
+        assert_eq!(
+            problems,
+            vec![Problem {
+                location: Some((4, 1)),
+                job: Some("build".to_string()),
+                warning: false,
+                message: "the job `build` has a `retry_on` but no `retries`, so it's never retried"
+                    .to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_reports_type_errors() {
+        let problems = check_local_config(Path::new("."), "[jobs.build]\nrun = 5\n", None, None);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].location, Some((2, 7)));
+        assert!(problems[0].message.contains("invalid type: integer `5`"));
+    }
+
+    #[test]
+    fn test_only_fails_on_the_job_being_run() {
+        let tmp = TempDir::new(".validate")
+            .context("Can't create a tmp dir")
+            .unwrap();
+        fs::write(
+            tmp.path().join("buildrecall.toml"),
+            "[jobs.build]\nrun = \"make\"\n\n[jobs.docs]\nextends = \"sphinx\"\nrun = \"make docs\"\n\n[containers.default]\nimage = \"rust:1.55\"\n",
+        )
+        .unwrap();
+
+        let problems = check_local_config(
+            tmp.path(),
+            &fs::read_to_string(tmp.path().join("buildrecall.toml")).unwrap(),
+            None,
+            None,
+        );
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].location, Some((5, 1)));
+        assert_eq!(problems[0].job.as_deref(), Some("docs"));
+        assert!(problems[0]
+            .message
+            .contains("There's no template named `sphinx`"));
+
+        let dir = tmp.path().to_path_buf();
+        assert!(validate_local_config(dir.clone(), Some("build"), Some("default")).is_ok());
+        assert!(validate_local_config(dir.clone(), Some("docs"), Some("default")).is_err());
+        assert!(validate_local_config(dir, None, None).is_err());
+    }
+}